@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS hub_presence
+(
+    replica VARCHAR (255) PRIMARY KEY NOT NULL,
+    clients BIGINT NOT NULL,
+    heartbeat_at TIMESTAMP with time zone DEFAULT (now() at time zone 'utc')
+);
+
+CREATE SEQUENCE IF NOT EXISTS hub_event_seq;
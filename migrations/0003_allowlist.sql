@@ -0,0 +1,5 @@
+-- Allowlist tier and allocation (e.g. number of mints) per sign-up
+ALTER TABLE vip_signups ADD COLUMN IF NOT EXISTS tier VARCHAR (32);
+ALTER TABLE vip_signups ADD COLUMN IF NOT EXISTS allocation INTEGER NOT NULL DEFAULT 1;
+
+CREATE INDEX IF NOT EXISTS vip_signups_signed_up_at ON vip_signups (signed_up_at, address);
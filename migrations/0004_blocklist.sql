@@ -0,0 +1,21 @@
+-- Addresses refused sign-up, such as sanctioned wallets, by the source of the listing (e.g. a loaded file)
+CREATE TABLE IF NOT EXISTS blocklist
+(
+    address VARCHAR (40) NOT NULL,
+    source VARCHAR (64) NOT NULL,
+    reason TEXT,
+    added_at TIMESTAMP with time zone DEFAULT (now() at time zone 'utc'),
+    PRIMARY KEY (address, source)
+);
+
+-- Audit of requests refused due to the blocklist
+CREATE TABLE IF NOT EXISTS blocked_attempts
+(
+    id BIGSERIAL PRIMARY KEY,
+    address VARCHAR (40) NOT NULL,
+    request VARCHAR (32) NOT NULL,
+    source TEXT NOT NULL,
+    attempted_at TIMESTAMP with time zone DEFAULT (now() at time zone 'utc')
+);
+
+CREATE INDEX IF NOT EXISTS blocked_attempts_address ON blocked_attempts (address);
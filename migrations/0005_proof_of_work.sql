@@ -0,0 +1,8 @@
+-- Proof-of-work challenges which have been redeemed, retained until they expire so that each can only be used once
+CREATE TABLE IF NOT EXISTS pow_redemptions
+(
+    id VARCHAR (32) PRIMARY KEY NOT NULL,
+    expires_at TIMESTAMP with time zone NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS pow_redemptions_expires_at ON pow_redemptions (expires_at);
@@ -0,0 +1,17 @@
+-- Request metadata recorded with sign-ups (when enabled) for sybil analysis, with client IPs only stored as salted hashes
+CREATE TABLE IF NOT EXISTS vip_signup_metadata
+(
+    address VARCHAR (40) PRIMARY KEY NOT NULL REFERENCES vip_signups (address) ON DELETE CASCADE,
+    ip_hash VARCHAR (64),
+    user_agent VARCHAR (512),
+    origin VARCHAR (255),
+    utm_source VARCHAR (255),
+    utm_medium VARCHAR (255),
+    utm_campaign VARCHAR (255),
+    utm_term VARCHAR (255),
+    utm_content VARCHAR (255),
+    connection_id VARCHAR (255),
+    recorded_at TIMESTAMP with time zone DEFAULT (now() at time zone 'utc')
+);
+
+CREATE INDEX IF NOT EXISTS vip_signup_metadata_ip_hash ON vip_signup_metadata (ip_hash);
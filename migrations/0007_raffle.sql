@@ -0,0 +1,19 @@
+-- Raffles drawn over the sign-ups, recording everything required to re-run the draw
+CREATE TABLE IF NOT EXISTS raffles
+(
+    id BIGSERIAL PRIMARY KEY,
+    seed TEXT NOT NULL,
+    list_hash VARCHAR (64) NOT NULL,
+    entries INTEGER NOT NULL,
+    cutoff TIMESTAMP with time zone,
+    drawn_at TIMESTAMP with time zone DEFAULT (now() at time zone 'utc')
+);
+
+-- The winners of each raffle, in the order drawn
+CREATE TABLE IF NOT EXISTS raffle_winners
+(
+    raffle_id BIGINT NOT NULL REFERENCES raffles (id) ON DELETE CASCADE,
+    position INTEGER NOT NULL,
+    address VARCHAR (40) NOT NULL,
+    PRIMARY KEY (raffle_id, position)
+);
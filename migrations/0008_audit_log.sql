@@ -0,0 +1,67 @@
+-- Append-only record of status changes, admin actions and changes to sign-ups
+CREATE TABLE IF NOT EXISTS audit_log
+(
+    id BIGSERIAL PRIMARY KEY,
+    occurred_at TIMESTAMP with time zone NOT NULL DEFAULT (now() at time zone 'utc'),
+    actor VARCHAR (255) NOT NULL,
+    action VARCHAR (64) NOT NULL,
+    target TEXT,
+    before JSONB,
+    after JSONB,
+    metadata JSONB
+);
+
+CREATE INDEX IF NOT EXISTS audit_log_occurred_at ON audit_log (occurred_at);
+CREATE INDEX IF NOT EXISTS audit_log_action ON audit_log (action);
+CREATE INDEX IF NOT EXISTS audit_log_target ON audit_log (target);
+
+CREATE OR REPLACE FUNCTION audit_log_append_only() RETURNS trigger AS $$
+BEGIN
+    RAISE EXCEPTION 'audit_log is append-only';
+END
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS audit_log_append_only ON audit_log;
+CREATE TRIGGER audit_log_append_only BEFORE UPDATE OR DELETE ON audit_log
+    FOR EACH ROW EXECUTE FUNCTION audit_log_append_only();
+DROP TRIGGER IF EXISTS audit_log_no_truncate ON audit_log;
+CREATE TRIGGER audit_log_no_truncate BEFORE TRUNCATE ON audit_log
+    FOR EACH STATEMENT EXECUTE FUNCTION audit_log_append_only();
+
+-- Records changes to the table however they are made, attributed to the actor set for the transaction by the API
+-- (`audit.actor` and `audit.metadata`) or otherwise to the database user. The first argument names the action, with
+-- the operation appended, and the optional second the column identifying the target.
+CREATE OR REPLACE FUNCTION audit_change() RETURNS trigger AS $$
+DECLARE
+    before JSONB;
+    after JSONB;
+BEGIN
+    IF TG_OP IN ('UPDATE', 'DELETE') THEN
+        before := to_jsonb(OLD);
+    END IF;
+    IF TG_OP IN ('INSERT', 'UPDATE') THEN
+        after := to_jsonb(NEW);
+    END IF;
+    IF before = after THEN
+        RETURN NULL;
+    END IF;
+    INSERT INTO audit_log (actor, action, target, before, after, metadata)
+    VALUES (
+        coalesce(nullif(current_setting('audit.actor', true), ''), 'db:' || session_user),
+        TG_ARGV[0] || '.' || lower(TG_OP),
+        CASE WHEN TG_NARGS > 1 THEN coalesce(after, before) ->> TG_ARGV[1] END,
+        before,
+        after,
+        nullif(current_setting('audit.metadata', true), '')::jsonb
+    );
+    RETURN NULL;
+END
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS vip_audit ON vip;
+CREATE TRIGGER vip_audit AFTER INSERT OR UPDATE OR DELETE ON vip
+    FOR EACH ROW EXECUTE FUNCTION audit_change('status');
+-- Sign-ups themselves are not audited, only subsequent changes
+DROP TRIGGER IF EXISTS vip_signups_audit ON vip_signups;
+CREATE TRIGGER vip_signups_audit AFTER UPDATE OR DELETE ON vip_signups
+    FOR EACH ROW EXECUTE FUNCTION audit_change('sign-up', 'address');
@@ -0,0 +1,5 @@
+-- The campaign each sign-up was recorded against, with existing sign-ups belonging to the VIP campaign
+ALTER TABLE vip_signups ADD COLUMN IF NOT EXISTS campaign VARCHAR (64) NOT NULL DEFAULT 'vip';
+
+CREATE INDEX IF NOT EXISTS vip_signups_campaign ON vip_signups (campaign, signed_up_at, address);
+CREATE INDEX IF NOT EXISTS vip_signups_address_pattern ON vip_signups (address varchar_pattern_ops);
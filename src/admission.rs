@@ -0,0 +1,232 @@
+use crate::error::Error;
+use axum::http::HeaderMap;
+use ipnet::IpNet;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+// Number of addresses with the most connections reported in usage
+const TOP_ADDRESSES: usize = 10;
+
+// Admission control for websocket connections, limiting the total number of connections and those per address
+pub struct Admission {
+    max_connections: usize,
+    max_connections_per_ip: usize,
+    trusted_proxies: Vec<IpNet>,
+    connections: Mutex<Connections>,
+}
+
+#[derive(Default)]
+struct Connections {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+impl Admission {
+    pub fn new(
+        max_connections: usize,
+        max_connections_per_ip: usize,
+        trusted_proxies: Vec<IpNet>,
+    ) -> Admission {
+        Admission {
+            max_connections,
+            max_connections_per_ip,
+            trusted_proxies,
+            connections: Mutex::new(Connections::default()),
+        }
+    }
+
+    // Determines the client address, using `X-Forwarded-For` when the peer is a trusted proxy
+    pub fn client_ip(&self, peer: SocketAddr, headers: &HeaderMap) -> IpAddr {
+        let peer = peer.ip();
+        if !self.trusted(&peer) {
+            return peer;
+        }
+
+        // Walk forwarded addresses from nearest to furthest, skipping trusted proxies. The walk stops at the first
+        // entry which is not a trusted address, as any entries beyond it may have been written by the client: should
+        // it not be a plain address (such as including a port or being `unknown`), the peer is used instead.
+        let forwarded: Vec<&str> = headers
+            .get_all(X_FORWARDED_FOR)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .collect();
+        let mut furthest = None;
+        for address in forwarded.iter().rev() {
+            match address.trim().parse::<IpAddr>() {
+                Ok(address) if self.trusted(&address) => furthest = Some(address),
+                Ok(address) => return address,
+                Err(_) => return peer,
+            }
+        }
+        furthest.unwrap_or(peer)
+    }
+
+    fn trusted(&self, address: &IpAddr) -> bool {
+        self.trusted_proxies
+            .iter()
+            .any(|proxy| proxy.contains(address))
+    }
+
+    // Admits a connection from the address, returning a permit which holds the connection slot until dropped
+    pub fn admit(self: &Arc<Self>, ip: IpAddr) -> crate::Result<Permit> {
+        let mut connections = self.connections.lock().expect("connections poisoned");
+        if connections.total >= self.max_connections {
+            return Err(Error::TooManyConnections);
+        }
+        let per_ip = connections.per_ip.entry(ip).or_default();
+        if *per_ip >= self.max_connections_per_ip {
+            return Err(Error::TooManyConnectionsFromAddress(ip));
+        }
+        *per_ip += 1;
+        connections.total += 1;
+
+        Ok(Permit {
+            admission: self.clone(),
+            ip,
+        })
+    }
+
+    fn release(&self, ip: IpAddr) {
+        let mut connections = self.connections.lock().expect("connections poisoned");
+        connections.total = connections.total.saturating_sub(1);
+        if let Some(per_ip) = connections.per_ip.get_mut(&ip) {
+            *per_ip -= 1;
+            if *per_ip == 0 {
+                connections.per_ip.remove(&ip);
+            }
+        }
+    }
+
+    pub fn usage(&self) -> Usage {
+        let connections = self.connections.lock().expect("connections poisoned");
+        let mut top: Vec<AddressUsage> = connections
+            .per_ip
+            .iter()
+            .map(|(address, connections)| AddressUsage {
+                address: *address,
+                connections: *connections,
+            })
+            .collect();
+        top.sort_by_key(|usage| std::cmp::Reverse(usage.connections));
+        top.truncate(TOP_ADDRESSES);
+
+        Usage {
+            connections: connections.total,
+            max_connections: self.max_connections,
+            addresses: connections.per_ip.len(),
+            max_connections_per_ip: self.max_connections_per_ip,
+            top,
+        }
+    }
+}
+
+// A connection slot, released when dropped
+pub struct Permit {
+    admission: Arc<Admission>,
+    ip: IpAddr,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.admission.release(self.ip);
+    }
+}
+
+#[derive(Serialize)]
+pub struct Usage {
+    pub connections: usize,
+    pub max_connections: usize,
+    pub addresses: usize,
+    pub max_connections_per_ip: usize,
+    pub top: Vec<AddressUsage>,
+}
+
+#[derive(Serialize)]
+pub struct AddressUsage {
+    pub address: IpAddr,
+    pub connections: usize,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn admission(trusted_proxies: &[&str]) -> Admission {
+        Admission::new(
+            10,
+            10,
+            trusted_proxies.iter().map(|p| p.parse().unwrap()).collect(),
+        )
+    }
+
+    fn forwarded(values: &[&str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for value in values {
+            headers.append(X_FORWARDED_FOR, HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    fn peer(address: &str) -> SocketAddr {
+        SocketAddr::new(address.parse().unwrap(), 443)
+    }
+
+    fn ip(address: &str) -> IpAddr {
+        address.parse().unwrap()
+    }
+
+    #[test]
+    fn forwarded_addresses_are_ignored_from_untrusted_peers() {
+        let admission = admission(&["10.0.0.0/8"]);
+        let headers = forwarded(&["1.2.3.4"]);
+        assert_eq!(
+            admission.client_ip(peer("203.0.113.1"), &headers),
+            ip("203.0.113.1")
+        );
+        assert_eq!(
+            admission.client_ip(peer("10.0.0.1"), &HeaderMap::new()),
+            ip("10.0.0.1")
+        );
+    }
+
+    #[test]
+    fn chained_trusted_proxies_are_skipped() {
+        let admission = admission(&["10.0.0.0/8", "192.168.0.0/16"]);
+        // The client's own entry is ignored in favour of that appended by the furthest trusted proxy
+        let headers = forwarded(&["6.6.6.6, 1.2.3.4, 192.168.1.1", "10.0.0.2"]);
+        assert_eq!(
+            admission.client_ip(peer("10.0.0.1"), &headers),
+            ip("1.2.3.4")
+        );
+
+        // Every entry being trusted leaves the furthest
+        let headers = forwarded(&["192.168.1.1, 10.0.0.2"]);
+        assert_eq!(
+            admission.client_ip(peer("10.0.0.1"), &headers),
+            ip("192.168.1.1")
+        );
+    }
+
+    #[test]
+    fn malformed_entries_stop_the_walk() {
+        let admission = admission(&["10.0.0.0/8"]);
+        for entry in ["1.2.3.4:5678", "[::1]:443", "unknown", ""] {
+            // A client cannot get past an entry the proxy wrote by prefixing one of its own
+            let headers = forwarded(&[&format!("6.6.6.6, {}", entry)]);
+            assert_eq!(
+                admission.client_ip(peer("10.0.0.1"), &headers),
+                ip("10.0.0.1")
+            );
+        }
+        let headers = forwarded(&["unknown, 2001:db8::1 , 10.0.0.2"]);
+        assert_eq!(
+            admission.client_ip(peer("10.0.0.1"), &headers),
+            ip("2001:db8::1")
+        );
+    }
+}
@@ -0,0 +1,83 @@
+use crate::db;
+use crate::models::Actor;
+use primitive_types::H160;
+use std::collections::BTreeSet;
+use std::path::Path;
+use std::str::FromStr;
+
+// Extracts the addresses within the contents, allowing lists with an address per line as well as extracts such as the
+// OFAC SDN list, where addresses appear within other text (e.g. `Digital Currency Address - ETH 0x...;`)
+pub fn parse(contents: &str) -> Vec<H160> {
+    let mut addresses = BTreeSet::new();
+    for line in contents.lines() {
+        let line = line.trim();
+        // Bare addresses (without the prefix) are only recognised on their own
+        if line.len() == 40 {
+            if let Ok(address) = H160::from_str(line) {
+                addresses.insert(address);
+                continue;
+            }
+        }
+
+        let bytes = line.as_bytes();
+        let mut start = 0;
+        while let Some(i) = line[start..].find("0x").map(|i| start + i) {
+            let end = i + 42;
+            // The address must not be part of a longer token, such as a transaction hash
+            let bounded = |index: Option<&u8>| index.is_none_or(|c| !c.is_ascii_alphanumeric());
+            let preceding = i.checked_sub(1).and_then(|i| bytes.get(i));
+            if bounded(preceding) && bounded(bytes.get(end)) {
+                // The end may fall within a multi-byte character, such as within accented names
+                if let Some(Ok(address)) = line.get(i + 2..end).map(H160::from_str) {
+                    addresses.insert(address);
+                }
+            }
+            start = i + 2;
+        }
+    }
+    addresses.into_iter().collect()
+}
+
+// Replaces the addresses listed by the source with those within the file
+pub async fn load(
+    pool: &db::ConnectionPool,
+    path: &Path,
+    source: &str,
+    actor: &Actor,
+) -> crate::Result<db::blocklist::BlocklistChange> {
+    let addresses = parse(&tokio::fs::read_to_string(path).await?);
+    let mut connection = pool.get_connection().await?;
+    let reason = format!("listed in {}", path.display());
+    db::blocklist::replace(&mut connection, source, &addresses, Some(&reason), actor).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse;
+    use primitive_types::H160;
+    use std::str::FromStr;
+
+    #[test]
+    fn parses_addresses_within_text() {
+        let address = H160::from_str("7f367cc41522ce07553e823bf3be79a889debe1b").unwrap();
+        let contents = "7F367CC41522CE07553E823BF3BE79A889DEBE1B
+            Digital Currency Address - ETH 0x7F367cC41522cE07553e823bf3be79A889DEbe1B; alt. Digital Currency Address
+            tx 0x7f367cc41522ce07553e823bf3be79a889debe1b00";
+        assert_eq!(parse(contents), vec![address]);
+    }
+
+    #[test]
+    fn ignores_non_ascii_text_following_the_prefix() {
+        // 39 ASCII characters followed by a multi-byte character spanning the end of a would-be address
+        let contents = format!(
+            "José Ramírez 0x{}é; Digital Currency Address - ETH 0x{};",
+            "a".repeat(39),
+            "b".repeat(40)
+        );
+        assert_eq!(
+            parse(&contents),
+            vec![H160::from_str(&"b".repeat(40)).unwrap()]
+        );
+        assert!(parse("0xé").is_empty());
+    }
+}
@@ -0,0 +1,441 @@
+use crate::error::Error;
+use crate::hub::{self, Message};
+use crate::import::{self, RowResult};
+use crate::models::{Actor, AllowlistQuery, FingerprintField, Raffle, Snapshot, Status};
+use crate::{blocklist, db, export, raffle};
+use chrono::{DateTime, NaiveDate, Utc};
+use primitive_types::H160;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::Path;
+use std::time::Duration;
+use tokio_postgres::IsolationLevel;
+
+// Applies pending migrations, or reports their status
+pub async fn migrate(pool: &db::ConnectionPool, status: bool, dry_run: bool) -> crate::Result<()> {
+    let mut connection = pool.get_connection().await?;
+
+    if status {
+        let migrations = db::migrations::status(&connection).await?;
+        for migration in migrations {
+            let state = match (migration.applied_at, migration.known) {
+                (Some(applied_at), true) => format!("applied {}", applied_at.to_rfc3339()),
+                (Some(applied_at), false) => {
+                    format!("applied {} (unknown)", applied_at.to_rfc3339())
+                }
+                (None, true) => "pending".to_string(),
+                (None, false) => "applied (unknown)".to_string(),
+            };
+            println!("{:>4}  {:<20} {}", migration.version, migration.name, state);
+        }
+        return Ok(());
+    }
+
+    let applied = db::migrations::migrate(&mut connection, dry_run).await?;
+    let verb = if dry_run { "would apply" } else { "applied" };
+    if applied.is_empty() {
+        println!("database is up to date");
+    }
+    for migration in applied {
+        println!("{} {:>4}  {}", verb, migration.version, migration.name);
+    }
+    Ok(())
+}
+
+// Reports the sign-up status, opening or closing sign-ups if requested
+pub async fn status(pool: &db::ConnectionPool, change: Option<Status>) -> crate::Result<()> {
+    let mut connection = pool.get_connection().await?;
+    if let Some(status) = change {
+        db::vip::set_status(&mut connection, status, &Actor::cli()).await?;
+        announce(&connection).await;
+    }
+    println!("{:?}", db::vip::status(&connection).await?);
+    Ok(())
+}
+
+// Reports the sign-up status of the SQLite store, opening or closing sign-ups if requested
+#[cfg(feature = "sqlite")]
+pub async fn sqlite_status(path: &Path, change: Option<Status>) -> crate::Result<()> {
+    use crate::store::SignUpStore;
+
+    let store = crate::store::sqlite::SqliteStore::open(path)?;
+    if let Some(status) = change {
+        store.set_status(status).await?;
+    }
+    println!("{:?}", store.total().await?.status);
+    Ok(())
+}
+
+// Streams the allowlist in the requested format, reporting the SHA-256 of the export and recording it alongside any
+// output file (in `sha256sum` format) so that the list can be verified later
+pub async fn export(
+    pool: &db::ConnectionPool,
+    query: &AllowlistQuery,
+    format: export::Format,
+    fields: &[export::Field],
+    output: Option<&Path>,
+) -> crate::Result<()> {
+    let mut connection = pool.get_connection().await?;
+    // Read within a single snapshot so that any count written up front matches the entries which follow
+    let transaction = connection
+        .build_transaction()
+        .isolation_level(IsolationLevel::RepeatableRead)
+        .read_only(true)
+        .start()
+        .await
+        .map_err(Error::DatabaseQuery)?;
+    let summary = export::write(&transaction, query, format, fields, writer(output)?).await?;
+    transaction.commit().await.map_err(Error::DatabaseQuery)?;
+
+    if let Some(path) = output {
+        let mut hash_file = path.as_os_str().to_owned();
+        hash_file.push(".sha256");
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        std::fs::write(hash_file, format!("{}  {}\n", summary.sha256, name))?;
+    }
+    eprintln!(
+        "exported {} sign-ups, sha256 {}",
+        summary.total, summary.sha256
+    );
+    Ok(())
+}
+
+// Signs up the addresses within a CSV or JSON file (or stdin), regardless of status, reporting any duplicate or invalid
+// rows along with the full report if requested
+pub async fn import(
+    pool: &db::ConnectionPool,
+    input: &Path,
+    format: Option<import::Format>,
+    dry_run: bool,
+    report: Option<&Path>,
+) -> crate::Result<()> {
+    let stdin = input.to_str() == Some("-");
+    let format = format.unwrap_or_else(|| {
+        match input.extension().and_then(|extension| extension.to_str()) {
+            Some(extension) if extension.eq_ignore_ascii_case("json") => import::Format::Json,
+            _ => import::Format::Csv,
+        }
+    });
+    let mut contents = String::new();
+    match stdin {
+        true => io::stdin().read_to_string(&mut contents)?,
+        false => File::open(input)?.read_to_string(&mut contents)?,
+    };
+    let rows = import::parse(format, &contents)?;
+
+    let mut connection = pool.get_connection().await?;
+    let summary = import::import(&mut connection, rows, dry_run, &Actor::cli()).await?;
+    if summary.accepted > 0 && !dry_run {
+        announce(&connection).await;
+    }
+
+    for row in summary
+        .rows
+        .iter()
+        .filter(|row| row.result != RowResult::Accepted)
+    {
+        println!(
+            "{:>6}  {:<9}  {}  {}",
+            row.row,
+            row.result,
+            row.address.as_deref().unwrap_or(&row.input),
+            row.reason.as_deref().unwrap_or_default()
+        );
+    }
+    if let Some(path) = report {
+        let mut writer = writer(Some(path))?;
+        serde_json::to_writer_pretty(&mut writer, &summary)?;
+        writeln!(writer)?;
+        writer.flush()?;
+    }
+    println!(
+        "{} {} sign-ups, {} duplicate, {} invalid",
+        if dry_run { "would import" } else { "imported" },
+        summary.accepted,
+        summary.duplicate,
+        summary.invalid
+    );
+    Ok(())
+}
+
+// Writes a consistent JSON snapshot of the status and all sign-ups
+pub async fn snapshot(pool: &db::ConnectionPool, output: Option<&Path>) -> crate::Result<()> {
+    let mut connection = pool.get_connection().await?;
+    let snapshot = db::vip::snapshot(&mut connection).await?;
+    let mut writer = writer(output)?;
+    serde_json::to_writer_pretty(&mut writer, &snapshot)?;
+    writeln!(writer)?;
+    writer.flush()?;
+    eprintln!(
+        "snapshot of {} sign-ups taken at {}",
+        snapshot.total,
+        snapshot.taken_at.to_rfc3339()
+    );
+    Ok(())
+}
+
+pub async fn stats(pool: &db::ConnectionPool) -> crate::Result<()> {
+    let connection = pool.get_connection().await?;
+    let stats = db::vip::stats(&connection).await?;
+    let clients = db::presence::total(&connection, hub::HEARTBEAT_EXPIRY).await?;
+    let timestamp = |value: Option<DateTime<Utc>>| {
+        value.map_or_else(|| "-".to_string(), |value| value.to_rfc3339())
+    };
+    println!("status            {:?}", stats.status);
+    println!("sign-ups          {}", stats.total);
+    println!("last hour         {}", stats.last_hour);
+    println!("last day          {}", stats.last_day);
+    println!("first signed up   {}", timestamp(stats.first_signed_up));
+    println!("last signed up    {}", timestamp(stats.last_signed_up));
+    println!("connected clients {}", clients);
+    Ok(())
+}
+
+pub async fn check(pool: &db::ConnectionPool, address: H160) -> crate::Result<()> {
+    let connection = pool.get_connection().await?;
+    match db::vip::get(&connection, address).await? {
+        Some(sign_up) => println!(
+            "{:#x} signed up at {}",
+            sign_up.address,
+            sign_up.signed_up_at.to_rfc3339()
+        ),
+        None => println!("{:#x} not signed up", address),
+    }
+    if let Some(sources) = db::blocklist::blocked(&*connection, &[address])
+        .await?
+        .get(&address)
+    {
+        println!("{:#x} blocked by {}", address, sources);
+    }
+    Ok(())
+}
+
+// Removes the sign-ups of the addresses, publishing the updated totals and notifying any clients with the wallets
+pub async fn remove(
+    pool: &db::ConnectionPool,
+    addresses: &[H160],
+    reason: Option<&str>,
+    block: bool,
+) -> crate::Result<()> {
+    let mut connection = pool.get_connection().await?;
+    let block = block.then_some(db::blocklist::REMOVAL_SOURCE);
+    let removal = db::vip::remove(&mut connection, addresses, reason, block, &Actor::cli()).await?;
+    if !removal.removed.is_empty() {
+        announce(&connection).await;
+        for address in &removal.removed {
+            let message = Message::Removed {
+                address: *address,
+                reason: reason.map(str::to_string),
+            };
+            let published =
+                async { db::hub::publish(&connection, &serde_json::to_string(&message)?).await };
+            if let Err(e) = published.await {
+                tracing::warn!("unable to notify clients of removed {:#x}: {}", address, e);
+            }
+        }
+    }
+    for address in &removal.removed {
+        println!("{:#x} removed", address);
+    }
+    for address in &removal.not_found {
+        println!("{:#x} not signed up", address);
+    }
+    if let Some(source) = block {
+        println!("{} addresses blocked by {}", removal.blocked, source);
+    }
+    Ok(())
+}
+
+// Reports clusters of sign-ups sharing a fingerprint within the window, largest first
+pub async fn clusters(
+    pool: &db::ConnectionPool,
+    fields: &[FingerprintField],
+    window: u64,
+    min_size: u64,
+    limit: u64,
+    json: bool,
+) -> crate::Result<()> {
+    if fields.is_empty() || window == 0 || min_size < 2 {
+        return Err(Error::InvalidQuery(
+            "expected fingerprint fields, a window greater than zero and a min size of at least 2"
+                .into(),
+        ));
+    }
+    let connection = pool.get_connection().await?;
+    let clusters = db::metadata::clusters(
+        &*connection,
+        fields,
+        Duration::from_secs(window),
+        min_size,
+        limit,
+    )
+    .await?;
+    if json {
+        serde_json::to_writer_pretty(io::stdout().lock(), &clusters)?;
+        println!();
+        return Ok(());
+    }
+    for cluster in &clusters {
+        println!(
+            "{} sign-ups from {} to {} sharing {}",
+            cluster.size,
+            cluster.first_signed_up.to_rfc3339(),
+            cluster.last_signed_up.to_rfc3339(),
+            cluster.fingerprint
+        );
+        for address in &cluster.addresses {
+            println!("  {}", export::checksum(address));
+        }
+    }
+    println!("{} clusters", clusters.len());
+    Ok(())
+}
+
+// Draws the raffle over a snapshot of the sign-ups, recording the result and writing the snapshot, from which anyone
+// can verify the draw, if an output is specified
+pub async fn raffle_draw(
+    pool: &db::ConnectionPool,
+    seed: &str,
+    winners: usize,
+    cutoff: Option<DateTime<Utc>>,
+    snapshot: Option<&Path>,
+) -> crate::Result<()> {
+    let mut connection = pool.get_connection().await?;
+    let taken = db::vip::snapshot(&mut connection).await?;
+    let draw = raffle::draw(seed, &raffle::entries(&taken, cutoff)?, winners)?;
+    if let Some(output) = snapshot {
+        let mut writer = writer(Some(output))?;
+        serde_json::to_writer_pretty(&mut writer, &taken)?;
+        writeln!(writer)?;
+        writer.flush()?;
+    }
+    let raffle =
+        db::raffle::insert(&mut connection, seed.trim(), cutoff, &draw, &Actor::cli()).await?;
+    print_raffle(&raffle);
+    Ok(())
+}
+
+pub async fn raffle_show(pool: &db::ConnectionPool, id: i64, json: bool) -> crate::Result<()> {
+    let connection = pool.get_connection().await?;
+    let raffle = db::raffle::get(&connection, id)
+        .await?
+        .ok_or_else(|| Error::InvalidRaffle(format!("raffle {} not found", id)))?;
+    if json {
+        serde_json::to_writer_pretty(io::stdout().lock(), &raffle)?;
+        println!();
+        return Ok(());
+    }
+    print_raffle(&raffle);
+    Ok(())
+}
+
+// Re-runs a draw from a snapshot, without requiring the database, failing if the list differs from that expected
+pub fn raffle_verify(
+    snapshot: &Path,
+    seed: &str,
+    winners: usize,
+    cutoff: Option<DateTime<Utc>>,
+    list_hash: Option<&str>,
+) -> crate::Result<()> {
+    let snapshot: Snapshot = serde_json::from_reader(io::BufReader::new(File::open(snapshot)?))?;
+    let draw = raffle::draw(seed, &raffle::entries(&snapshot, cutoff)?, winners)?;
+    if let Some(expected) = list_hash {
+        if !expected.eq_ignore_ascii_case(&draw.list_hash) {
+            return Err(Error::InvalidRaffle(format!(
+                "list hash {} does not match the expected {}",
+                draw.list_hash, expected
+            )));
+        }
+    }
+    println!("list hash {}", draw.list_hash);
+    println!("entries   {}", draw.entries);
+    for (position, address) in draw.winners.iter().enumerate() {
+        println!("{:>5} {}", position + 1, export::checksum(address));
+    }
+    Ok(())
+}
+
+fn print_raffle(raffle: &Raffle) {
+    println!("raffle    {}", raffle.id);
+    println!("drawn at  {}", raffle.drawn_at.to_rfc3339());
+    println!("seed      {}", raffle.seed);
+    if let Some(cutoff) = raffle.cutoff {
+        println!("cutoff    {}", cutoff.to_rfc3339());
+    }
+    println!("list hash {}", raffle.list_hash);
+    println!("entries   {}", raffle.entries);
+    for (position, address) in raffle.winners.iter().enumerate() {
+        println!("{:>5} {}", position + 1, export::checksum(address));
+    }
+}
+
+// Replaces the addresses listed by the source with those within the file, taking effect without a restart
+pub async fn blocklist_load(
+    pool: &db::ConnectionPool,
+    file: &Path,
+    source: &str,
+) -> crate::Result<()> {
+    let change = blocklist::load(pool, file, source, &Actor::cli()).await?;
+    println!(
+        "{}: {} added, {} removed, {} addresses blocked in total",
+        source, change.added, change.removed, change.total
+    );
+    Ok(())
+}
+
+pub async fn blocklist_add(
+    pool: &db::ConnectionPool,
+    address: H160,
+    source: &str,
+    reason: Option<&str>,
+) -> crate::Result<()> {
+    let mut connection = pool.get_connection().await?;
+    db::blocklist::add(&mut connection, address, source, reason, &Actor::cli()).await?;
+    println!("{:#x} blocked by {}", address, source);
+    Ok(())
+}
+
+pub async fn blocklist_remove(
+    pool: &db::ConnectionPool,
+    address: H160,
+    source: Option<&str>,
+) -> crate::Result<()> {
+    let mut connection = pool.get_connection().await?;
+    match db::blocklist::remove(&mut connection, address, source, &Actor::cli()).await? {
+        0 => println!("{:#x} not blocked", address),
+        _ => println!("{:#x} unblocked", address),
+    }
+    Ok(())
+}
+
+// Publishes the updated totals/status to clients connected to any replica
+async fn announce(connection: &db::Connection) {
+    let published = async {
+        let sign_ups = db::vip::total(connection).await?;
+        let message = Message::SignedUp {
+            total: sign_ups.total,
+            signed_up: None,
+            last_signed_up: sign_ups.last_signed_up,
+            status: sign_ups.status,
+        };
+        db::hub::publish(connection, &serde_json::to_string(&message)?).await
+    };
+    if let Err(e) = published.await {
+        tracing::warn!("unable to publish updated totals to clients: {}", e);
+    }
+}
+
+// Parses an RFC 3339 timestamp or a date, taken as midnight UTC
+pub fn parse_time(value: &str) -> Result<DateTime<Utc>, chrono::ParseError> {
+    match NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        Ok(date) => Ok(DateTime::from_utc(date.and_hms(0, 0, 0), Utc)),
+        Err(_) => Ok(DateTime::parse_from_rfc3339(value)?.with_timezone(&Utc)),
+    }
+}
+
+fn writer(output: Option<&Path>) -> crate::Result<Box<dyn Write>> {
+    Ok(match output {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(BufWriter::new(io::stdout())),
+    })
+}
@@ -0,0 +1,874 @@
+use crate::db;
+use crate::error::Error::InvalidConfiguration;
+use crate::hub::{self, Topic};
+use crate::pow;
+use crate::rate_limit::{Limit, RateLimits, Scope};
+use ipnet::IpNet;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fmt::Display;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use std::{env, fs};
+
+// Configuration file used when none specified, if present
+const DEFAULT_CONFIG_FILE: &str = "metafashion.toml";
+const CONFIG_FILE: &str = "CONFIG_FILE";
+
+// Source of environment variables, injectable so that the process environment is not required
+type Vars<'a> = dyn Fn(&str) -> Option<OsString> + 'a;
+
+// Configuration, loaded from an optional TOML file and then overridden by environment variables. Any environment
+// variable may instead be read from a file named by the same variable with a `_FILE` suffix (e.g. `API_KEY_FILE`).
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub hub: HubConfig,
+    pub admin: AdminConfig,
+    pub database: DatabaseConfig,
+    pub store: StoreConfig,
+    pub limits: LimitsConfig,
+    pub blocklist: BlocklistConfig,
+    pub proof_of_work: ProofOfWorkConfig,
+    pub metadata: MetadataConfig,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind: SocketAddr,
+    // Log filter directives, overridden by `RUST_LOG`
+    pub log: String,
+    // Seconds after which clients are asked to reconnect when shutting down
+    pub reconnect_delay: u64,
+    // Seconds allowed for connections to drain when shutting down
+    pub shutdown_timeout: u64,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            bind: SocketAddr::from(([0, 0, 0, 0], 8000)),
+            log: "debug,tower_http=debug".into(),
+            reconnect_delay: 5,
+            shutdown_timeout: 30,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct HubConfig {
+    pub api_key: Option<String>,
+    pub default_topics: Vec<Topic>,
+}
+
+impl Default for HubConfig {
+    fn default() -> Self {
+        HubConfig {
+            api_key: None,
+            default_topics: vec![
+                Topic::Totals,
+                Topic::Presence,
+                Topic::Status,
+                Topic::Announcements,
+            ],
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    // The admin API is only enabled when a key is configured
+    pub api_key: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    pub connection_string: Option<String>,
+    // Seconds to wait for the database to become available on startup
+    pub startup_timeout: u64,
+    pub tls: db::tls::TlsConfig,
+    pub pool: PoolConfig,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        DatabaseConfig {
+            connection_string: None,
+            startup_timeout: 60,
+            tls: db::tls::TlsConfig::default(),
+            pool: PoolConfig::default(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct PoolConfig {
+    pub max_size: u32,
+    pub min_idle: Option<u32>,
+    // Seconds to wait for a connection from the pool
+    pub connection_timeout: u64,
+    // Seconds after which connections are closed, with zero disabling the limit
+    pub max_lifetime: u64,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        let defaults = db::PoolConfig::default();
+        PoolConfig {
+            max_size: defaults.max_size,
+            min_idle: defaults.min_idle,
+            connection_timeout: defaults.connection_timeout.as_secs(),
+            max_lifetime: defaults
+                .max_lifetime
+                .map_or(0, |lifetime| lifetime.as_secs()),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    Postgres,
+    Memory,
+    Sqlite,
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim() {
+            "postgres" => Ok(Backend::Postgres),
+            "memory" => Ok(Backend::Memory),
+            "sqlite" => Ok(Backend::Sqlite),
+            _ => Err(format!(
+                "unsupported backend {}, expected postgres, memory or sqlite",
+                value
+            )),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct StoreConfig {
+    pub backend: Backend,
+    pub sqlite_path: PathBuf,
+}
+
+impl Default for StoreConfig {
+    fn default() -> Self {
+        StoreConfig {
+            backend: Backend::Postgres,
+            sqlite_path: "metafashion.db".into(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    pub max_connections: usize,
+    pub max_connections_per_ip: usize,
+    // Proxies (addresses or networks) trusted to report the client address via `X-Forwarded-For`
+    pub trusted_proxies: Vec<String>,
+    // Rate limits overriding the defaults, keyed by request and then scope (e.g. `sign-up.ip = "20/60"`)
+    pub rate: HashMap<String, HashMap<String, Limit>>,
+    pub violations: Option<Limit>,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        LimitsConfig {
+            max_connections: 10_000,
+            max_connections_per_ip: 20,
+            trusted_proxies: Vec::new(),
+            rate: HashMap::new(),
+            violations: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct BlocklistConfig {
+    // File of addresses to block (such as an OFAC SDN extract), loaded on startup and reloaded on SIGHUP
+    pub file: Option<PathBuf>,
+    // Source recorded against the addresses loaded from the file, so that they can be replaced on reload
+    pub source: String,
+}
+
+impl Default for BlocklistConfig {
+    fn default() -> Self {
+        BlocklistConfig {
+            file: None,
+            source: "file".into(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProofOfWorkConfig {
+    // Requires sign-ups to include the solution to a challenge
+    pub enabled: bool,
+    // Key used to sign challenges, which must be shared by all replicas
+    pub secret: Option<String>,
+    // Leading zero bits required of solutions while the sign-up rate is below the threshold
+    pub difficulty: u32,
+    pub max_difficulty: u32,
+    // Sign-ups per minute after which the difficulty increases, by a bit each time the rate doubles
+    pub threshold: u32,
+    // Seconds for which challenges remain valid
+    pub expiry: u64,
+}
+
+impl Default for ProofOfWorkConfig {
+    fn default() -> Self {
+        ProofOfWorkConfig {
+            enabled: false,
+            secret: None,
+            difficulty: 16,
+            max_difficulty: 24,
+            threshold: 60,
+            expiry: 120,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetadataConfig {
+    // Records request metadata (hashed IP, user agent, origin, UTM parameters, connection) with each sign-up
+    pub enabled: bool,
+    // Salt with which client IPs are hashed, so that they are not stored but sign-ups from the same IP can be related
+    pub ip_salt: Option<String>,
+}
+
+impl Config {
+    // Loads configuration from the file (or `CONFIG_FILE`/default file if present), applying environment overrides
+    pub fn load(path: Option<PathBuf>) -> crate::Result<Config> {
+        Config::load_from(path, &|name| env::var_os(name))
+    }
+
+    fn load_from(path: Option<PathBuf>, vars: &Vars) -> crate::Result<Config> {
+        let path = match path.or_else(|| vars(CONFIG_FILE).map(PathBuf::from)) {
+            Some(path) => Some(path),
+            None => Some(PathBuf::from(DEFAULT_CONFIG_FILE)).filter(|path| path.exists()),
+        };
+        let mut config = match path {
+            Some(path) => Config::read(&path)?,
+            None => Config::default(),
+        };
+        config.apply_environment(vars)?;
+        Ok(config)
+    }
+
+    fn read(path: &Path) -> crate::Result<Config> {
+        let contents = fs::read_to_string(path).map_err(|e| {
+            InvalidConfiguration(format!("unable to read {}: {}", path.display(), e))
+        })?;
+        toml::from_str(&contents)
+            .map_err(|e| InvalidConfiguration(format!("{}: {}", path.display(), e)))
+    }
+
+    fn apply_environment(&mut self, vars: &Vars) -> crate::Result<()> {
+        parse(vars, "BIND_ADDRESS", &mut self.server.bind)?;
+        parse(vars, "RUST_LOG", &mut self.server.log)?;
+        parse(vars, "RECONNECT_DELAY", &mut self.server.reconnect_delay)?;
+        parse(vars, "SHUTDOWN_TIMEOUT", &mut self.server.shutdown_timeout)?;
+
+        optional(vars, "API_KEY", &mut self.hub.api_key)?;
+        if let Some(topics) = var(vars, "DEFAULT_TOPICS")? {
+            self.hub.default_topics = list(&topics)
+                .map(str::parse)
+                .collect::<crate::Result<_>>()
+                .map_err(|e| InvalidConfiguration(format!("DEFAULT_TOPICS invalid: {}", e)))?;
+        }
+        optional(vars, "ADMIN_API_KEY", &mut self.admin.api_key)?;
+
+        optional(
+            vars,
+            "CONNECTION_STRING",
+            &mut self.database.connection_string,
+        )?;
+        parse(
+            vars,
+            "DATABASE_STARTUP_TIMEOUT",
+            &mut self.database.startup_timeout,
+        )?;
+        parse(vars, "DATABASE_TLS", &mut self.database.tls.mode)?;
+        optional(vars, "DATABASE_CA_FILE", &mut self.database.tls.ca_file)?;
+        optional(
+            vars,
+            "DATABASE_CLIENT_CERT",
+            &mut self.database.tls.client_cert,
+        )?;
+        optional(
+            vars,
+            "DATABASE_CLIENT_KEY",
+            &mut self.database.tls.client_key,
+        )?;
+        parse(
+            vars,
+            "DATABASE_POOL_MAX_SIZE",
+            &mut self.database.pool.max_size,
+        )?;
+        optional(
+            vars,
+            "DATABASE_POOL_MIN_IDLE",
+            &mut self.database.pool.min_idle,
+        )?;
+        parse(
+            vars,
+            "DATABASE_POOL_CONNECTION_TIMEOUT",
+            &mut self.database.pool.connection_timeout,
+        )?;
+        parse(
+            vars,
+            "DATABASE_POOL_MAX_LIFETIME",
+            &mut self.database.pool.max_lifetime,
+        )?;
+
+        parse(vars, "STORE", &mut self.store.backend)?;
+        parse(vars, "SQLITE_PATH", &mut self.store.sqlite_path)?;
+
+        parse(vars, "MAX_CONNECTIONS", &mut self.limits.max_connections)?;
+        parse(
+            vars,
+            "MAX_CONNECTIONS_PER_IP",
+            &mut self.limits.max_connections_per_ip,
+        )?;
+        if let Some(proxies) = var(vars, "TRUSTED_PROXIES")? {
+            self.limits.trusted_proxies = list(&proxies).map(String::from).collect();
+        }
+        // Rate limits are overridden using RATE_LIMIT_<REQUEST>_<SCOPE> (e.g. RATE_LIMIT_SIGN_UP_IP=20/60)
+        for request in hub::REQUESTS {
+            for scope in Scope::ALL {
+                let name = format!("RATE_LIMIT_{}_{}", request, scope)
+                    .replace('-', "_")
+                    .to_uppercase();
+                let mut limit = None;
+                optional(vars, &name, &mut limit)?;
+                if let Some(limit) = limit {
+                    self.limits
+                        .rate
+                        .entry(request.to_string())
+                        .or_default()
+                        .insert(scope.to_string(), limit);
+                }
+            }
+        }
+        optional(vars, "RATE_LIMIT_VIOLATIONS", &mut self.limits.violations)?;
+
+        optional(vars, "BLOCKLIST_FILE", &mut self.blocklist.file)?;
+        parse(vars, "BLOCKLIST_SOURCE", &mut self.blocklist.source)?;
+
+        parse(vars, "POW_ENABLED", &mut self.proof_of_work.enabled)?;
+        optional(vars, "POW_SECRET", &mut self.proof_of_work.secret)?;
+        parse(vars, "POW_DIFFICULTY", &mut self.proof_of_work.difficulty)?;
+        parse(
+            vars,
+            "POW_MAX_DIFFICULTY",
+            &mut self.proof_of_work.max_difficulty,
+        )?;
+        parse(vars, "POW_THRESHOLD", &mut self.proof_of_work.threshold)?;
+        parse(vars, "POW_EXPIRY", &mut self.proof_of_work.expiry)?;
+        parse(vars, "SIGNUP_METADATA", &mut self.metadata.enabled)?;
+        optional(vars, "SIGNUP_METADATA_IP_SALT", &mut self.metadata.ip_salt)?;
+        Ok(())
+    }
+
+    // Validates the configuration required to serve the API
+    pub fn validate(&self) -> crate::Result<()> {
+        if self.hub.api_key.as_deref().is_none_or(str::is_empty) {
+            return Err(InvalidConfiguration(
+                "hub.api_key (API_KEY) must be set".into(),
+            ));
+        }
+        if self.admin.api_key.as_deref() == Some("") {
+            return Err(InvalidConfiguration(
+                "admin.api_key (ADMIN_API_KEY) cannot be empty".into(),
+            ));
+        }
+        match self.store.backend {
+            Backend::Postgres if self.database.connection_string.is_none() => {
+                return Err(InvalidConfiguration(
+                    "database.connection_string (CONNECTION_STRING) must be set when using the postgres store"
+                        .into(),
+                ))
+            }
+            Backend::Sqlite if !cfg!(feature = "sqlite") => {
+                return Err(InvalidConfiguration(
+                    "store.backend (STORE) sqlite requires the `sqlite` feature to be enabled at build time"
+                        .into(),
+                ))
+            }
+            _ => {}
+        }
+        if self.database.connection_string.is_some() {
+            self.validate_database()?;
+        }
+        if self.limits.max_connections == 0 || self.limits.max_connections_per_ip == 0 {
+            return Err(InvalidConfiguration(
+                "limits.max_connections (MAX_CONNECTIONS) and limits.max_connections_per_ip (MAX_CONNECTIONS_PER_IP) must be greater than zero"
+                    .into(),
+            ));
+        }
+        self.trusted_proxies()?;
+        self.rate_limits()?;
+        if let Some(file) = &self.blocklist.file {
+            // Only the postgres store consults the blocklist
+            if self.store.backend != Backend::Postgres {
+                return Err(InvalidConfiguration(
+                    "blocklist.file (BLOCKLIST_FILE) requires the postgres store".into(),
+                ));
+            }
+            if !file.is_file() {
+                return Err(InvalidConfiguration(format!(
+                    "blocklist file {} does not exist",
+                    file.display()
+                )));
+            }
+        }
+        if self.blocklist.source.is_empty() || self.blocklist.source.len() > 64 {
+            return Err(InvalidConfiguration(
+                "blocklist.source (BLOCKLIST_SOURCE) must be between 1 and 64 characters".into(),
+            ));
+        }
+        self.proof_of_work()?;
+        self.metadata_salt()?;
+        Ok(())
+    }
+
+    // Validates the configuration required to connect to the database
+    pub fn validate_database(&self) -> crate::Result<()> {
+        if self.database.connection_string.is_none() {
+            return Err(InvalidConfiguration(
+                "database.connection_string (CONNECTION_STRING) must be set".into(),
+            ));
+        }
+        let tls = &self.database.tls;
+        if matches!(tls.mode, db::tls::TlsMode::Custom) && tls.ca_file.is_none() {
+            return Err(InvalidConfiguration(
+                "database.tls.ca_file (DATABASE_CA_FILE) must be set when database.tls.mode is custom"
+                    .into(),
+            ));
+        }
+        if tls.client_cert.is_some() != tls.client_key.is_some() {
+            return Err(InvalidConfiguration(
+                "database.tls.client_cert (DATABASE_CLIENT_CERT) and database.tls.client_key (DATABASE_CLIENT_KEY) must be set together"
+                    .into(),
+            ));
+        }
+        for file in [&tls.ca_file, &tls.client_cert, &tls.client_key]
+            .into_iter()
+            .flatten()
+        {
+            if !file.is_file() {
+                return Err(InvalidConfiguration(format!(
+                    "database tls file {} does not exist",
+                    file.display()
+                )));
+            }
+        }
+        let pool = &self.database.pool;
+        if pool.max_size == 0 {
+            return Err(InvalidConfiguration(
+                "database.pool.max_size (DATABASE_POOL_MAX_SIZE) must be greater than zero".into(),
+            ));
+        }
+        if pool
+            .min_idle
+            .is_some_and(|min_idle| min_idle > pool.max_size)
+        {
+            return Err(InvalidConfiguration(
+                "database.pool.min_idle (DATABASE_POOL_MIN_IDLE) cannot exceed database.pool.max_size"
+                    .into(),
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn reconnect_delay(&self) -> Duration {
+        Duration::from_secs(self.server.reconnect_delay)
+    }
+
+    pub fn shutdown_timeout(&self) -> Duration {
+        Duration::from_secs(self.server.shutdown_timeout)
+    }
+
+    pub fn startup_timeout(&self) -> Duration {
+        Duration::from_secs(self.database.startup_timeout)
+    }
+
+    pub fn pool(&self) -> db::PoolConfig {
+        let pool = &self.database.pool;
+        db::PoolConfig {
+            max_size: pool.max_size,
+            min_idle: pool.min_idle,
+            connection_timeout: Duration::from_secs(pool.connection_timeout),
+            max_lifetime: Some(pool.max_lifetime)
+                .filter(|lifetime| *lifetime > 0)
+                .map(Duration::from_secs),
+        }
+    }
+
+    // Settings for proof-of-work challenges, if enabled
+    pub fn proof_of_work(&self) -> crate::Result<Option<pow::Settings>> {
+        let pow = &self.proof_of_work;
+        if !pow.enabled {
+            return Ok(None);
+        }
+        let secret =
+            match &pow.secret {
+                // At least 256 bits, matching the HMAC-SHA256 output
+                Some(secret) if secret.len() >= 32 => secret.clone(),
+                _ => return Err(InvalidConfiguration(
+                    "proof_of_work.secret (POW_SECRET) must be at least 32 characters when enabled"
+                        .into(),
+                )),
+            };
+        // Beyond 32 bits, solutions are impractical for browsers
+        if pow.difficulty > pow.max_difficulty || pow.max_difficulty > 32 {
+            return Err(InvalidConfiguration(
+                "proof_of_work.difficulty (POW_DIFFICULTY) cannot exceed proof_of_work.max_difficulty (POW_MAX_DIFFICULTY), which cannot exceed 32"
+                    .into(),
+            ));
+        }
+        if pow.threshold == 0 || pow.expiry == 0 {
+            return Err(InvalidConfiguration(
+                "proof_of_work.threshold (POW_THRESHOLD) and proof_of_work.expiry (POW_EXPIRY) must be greater than zero"
+                    .into(),
+            ));
+        }
+        Ok(Some(pow::Settings {
+            secret,
+            difficulty: pow.difficulty,
+            max_difficulty: pow.max_difficulty,
+            threshold: pow.threshold,
+            expiry: Duration::from_secs(pow.expiry),
+        }))
+    }
+
+    // Salt with which client IPs are hashed, when sign-up metadata is recorded
+    pub fn metadata_salt(&self) -> crate::Result<Option<String>> {
+        if !self.metadata.enabled {
+            return Ok(None);
+        }
+        // Only the postgres store records metadata
+        if self.store.backend != Backend::Postgres {
+            return Err(InvalidConfiguration(
+                "metadata.enabled (SIGNUP_METADATA) requires the postgres store".into(),
+            ));
+        }
+        match &self.metadata.ip_salt {
+            Some(salt) if salt.len() >= 16 => Ok(Some(salt.clone())),
+            _ => Err(InvalidConfiguration(
+                "metadata.ip_salt (SIGNUP_METADATA_IP_SALT) must be at least 16 characters when enabled"
+                    .into(),
+            )),
+        }
+    }
+
+    pub fn trusted_proxies(&self) -> crate::Result<Vec<IpNet>> {
+        self.limits
+            .trusted_proxies
+            .iter()
+            .map(|proxy| {
+                // Accept individual addresses as well as networks
+                proxy
+                    .parse::<IpNet>()
+                    .or_else(|_| proxy.parse::<IpAddr>().map(IpNet::from))
+                    .map_err(|e| {
+                        InvalidConfiguration(format!(
+                            "limits.trusted_proxies (TRUSTED_PROXIES) invalid: {}: {}",
+                            proxy, e
+                        ))
+                    })
+            })
+            .collect()
+    }
+
+    pub fn rate_limits(&self) -> crate::Result<RateLimits> {
+        let mut rate_limits = RateLimits::default();
+        for (request, limits) in &self.limits.rate {
+            let request = hub::REQUESTS
+                .into_iter()
+                .find(|r| r == request)
+                .ok_or_else(|| {
+                    InvalidConfiguration(format!("limits.rate: unknown request {}", request))
+                })?;
+            for (scope, limit) in limits {
+                let scope: Scope = scope.parse().map_err(|_| {
+                    InvalidConfiguration(format!(
+                        "limits.rate.{}: unknown scope {}, expected connection, ip or address",
+                        request, scope
+                    ))
+                })?;
+                rate_limits.set(request, scope, *limit);
+            }
+        }
+        if let Some(violations) = self.limits.violations {
+            rate_limits.violations = violations;
+        }
+        Ok(rate_limits)
+    }
+}
+
+// Reads the environment variable, or the contents of the file named by the `_FILE` variant of the variable
+fn var(vars: &Vars, name: &str) -> crate::Result<Option<String>> {
+    if let Some(value) = vars(name).and_then(|value| value.into_string().ok()) {
+        return Ok(Some(value));
+    }
+    let file = format!("{}_FILE", name);
+    match vars(&file) {
+        Some(path) => fs::read_to_string(&path)
+            .map(|value| Some(value.trim_end_matches(['\r', '\n']).to_string()))
+            .map_err(|e| {
+                InvalidConfiguration(format!(
+                    "{} unable to read {}: {}",
+                    file,
+                    Path::new(&path).display(),
+                    e
+                ))
+            }),
+        None => Ok(None),
+    }
+}
+
+fn parse<T>(vars: &Vars, name: &str, target: &mut T) -> crate::Result<()>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    if let Some(value) = var(vars, name)? {
+        *target = value
+            .trim()
+            .parse()
+            .map_err(|e| InvalidConfiguration(format!("{} invalid: {}", name, e)))?;
+    }
+    Ok(())
+}
+
+fn optional<T>(vars: &Vars, name: &str, target: &mut Option<T>) -> crate::Result<()>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    if let Some(value) = var(vars, name)? {
+        *target = Some(
+            value
+                .trim()
+                .parse()
+                .map_err(|e| InvalidConfiguration(format!("{} invalid: {}", name, e)))?,
+        );
+    }
+    Ok(())
+}
+
+// Splits a comma-separated list, ignoring empty items
+fn list(value: &str) -> impl Iterator<Item = &str> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+
+    // Loads the configuration from the (optional) TOML contents, with the environment consisting only of the variables
+    fn load(toml: Option<&str>, vars: &[(&str, &str)]) -> crate::Result<Config> {
+        let path = toml.map(|contents| temp_file("config.toml", contents));
+        let vars: HashMap<String, OsString> = vars
+            .iter()
+            .map(|(name, value)| (name.to_string(), OsString::from(value)))
+            .collect();
+        Config::load_from(path, &|name| vars.get(name).cloned())
+    }
+
+    fn temp_file(name: &str, contents: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!(
+            "metafashion-{}-{:?}-{}",
+            std::process::id(),
+            std::thread::current().id(),
+            name
+        ));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn message(result: crate::Result<impl std::fmt::Debug>) -> String {
+        match result {
+            Err(Error::InvalidConfiguration(message)) => message,
+            result => panic!("expected invalid configuration, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn the_example_configuration_is_valid() {
+        let config = load(Some(include_str!("../metafashion.example.toml")), &[]).unwrap();
+        assert_eq!(config.server.log, "info,tower_http=debug");
+        assert_eq!(config.hub.default_topics.len(), 4);
+    }
+
+    #[test]
+    fn environment_variables_override_the_file() {
+        let toml = r#"
+            [server]
+            bind = "127.0.0.1:9000"
+            reconnect_delay = 10
+
+            [hub]
+            api_key = "from-file"
+
+            [limits.rate.sign-up]
+            ip = "5/60"
+        "#;
+        let config = load(
+            Some(toml),
+            &[
+                ("API_KEY", " from-environment "),
+                ("RECONNECT_DELAY", "20"),
+                ("DEFAULT_TOPICS", "totals, ,stats"),
+                ("RATE_LIMIT_SIGN_UP_ADDRESS", "1/60"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(config.server.bind, "127.0.0.1:9000".parse().unwrap());
+        assert_eq!(config.server.reconnect_delay, 20);
+        // Values are trimmed when overridden
+        assert_eq!(config.hub.api_key.as_deref(), Some("from-environment"));
+        assert_eq!(config.hub.default_topics, [Topic::Totals, Topic::Stats]);
+        let rate = &config.limits.rate["sign-up"];
+        assert_eq!(rate["ip"].capacity, 5);
+        assert_eq!(rate["address"].capacity, 1);
+
+        // Without a file, the defaults are overridden
+        let config = load(None, &[("SHUTDOWN_TIMEOUT", "1")]).unwrap();
+        assert_eq!(config.server.shutdown_timeout, 1);
+        assert_eq!(config.server.reconnect_delay, 5);
+    }
+
+    #[test]
+    fn variables_may_be_read_from_files() {
+        let secret = temp_file("api-key", "secret value\r\n\n");
+        let secret = secret.to_str().unwrap();
+        let config = load(None, &[("API_KEY_FILE", secret)]).unwrap();
+        // Only trailing line breaks are removed from the file
+        assert_eq!(config.hub.api_key.as_deref(), Some("secret value"));
+
+        // The variable itself takes precedence over the file
+        let config = load(None, &[("API_KEY", "plain"), ("API_KEY_FILE", secret)]).unwrap();
+        assert_eq!(config.hub.api_key.as_deref(), Some("plain"));
+
+        let missing = env::temp_dir().join("metafashion-missing-secret");
+        assert!(message(load(
+            None,
+            &[("POW_SECRET_FILE", missing.to_str().unwrap())]
+        ))
+        .starts_with("POW_SECRET_FILE unable to read"));
+    }
+
+    #[test]
+    fn invalid_values_are_reported() {
+        assert!(message(load(None, &[("MAX_CONNECTIONS", "many")]))
+            .starts_with("MAX_CONNECTIONS invalid:"));
+        assert!(message(load(None, &[("DEFAULT_TOPICS", "totals,weather")]))
+            .starts_with("DEFAULT_TOPICS invalid:"));
+        assert!(message(load(Some("[server]\nport = 80\n"), &[])).contains("unknown field"));
+    }
+
+    #[test]
+    fn configurations_are_validated() {
+        let valid = [
+            ("API_KEY", "key"),
+            ("CONNECTION_STRING", "postgres://localhost/api"),
+        ];
+        load(None, &valid).unwrap().validate().unwrap();
+        load(None, &[("API_KEY", "key"), ("STORE", "memory")])
+            .unwrap()
+            .validate()
+            .unwrap();
+
+        let invalid = |vars: &[(&str, &str)]| {
+            let vars: Vec<_> = valid.iter().chain(vars).copied().collect();
+            message(load(None, &vars).unwrap().validate())
+        };
+        assert_eq!(
+            invalid(&[("API_KEY", "")]),
+            "hub.api_key (API_KEY) must be set"
+        );
+        assert_eq!(
+            invalid(&[("ADMIN_API_KEY", "")]),
+            "admin.api_key (ADMIN_API_KEY) cannot be empty"
+        );
+        assert_eq!(
+            message(load(None, &[("API_KEY", "key")]).unwrap().validate()),
+            "database.connection_string (CONNECTION_STRING) must be set when using the postgres store"
+        );
+        assert_eq!(
+            invalid(&[("TRUSTED_PROXIES", "10.0.0.0/8,proxy")]),
+            "limits.trusted_proxies (TRUSTED_PROXIES) invalid: proxy: invalid IP address syntax"
+        );
+        assert_eq!(
+            invalid(&[("POW_ENABLED", "true"), ("POW_SECRET", "short")]),
+            "proof_of_work.secret (POW_SECRET) must be at least 32 characters when enabled"
+        );
+        assert_eq!(
+            invalid(&[("SIGNUP_METADATA", "true"), ("STORE", "memory")]),
+            "metadata.enabled (SIGNUP_METADATA) requires the postgres store"
+        );
+    }
+
+    #[test]
+    fn database_configurations_are_validated() {
+        let invalid = |vars: &[(&str, &str)]| {
+            let vars: Vec<_> = [("CONNECTION_STRING", "postgres://localhost/api")]
+                .iter()
+                .chain(vars)
+                .copied()
+                .collect();
+            message(load(None, &vars).unwrap().validate_database())
+        };
+        assert_eq!(
+            message(load(None, &[]).unwrap().validate_database()),
+            "database.connection_string (CONNECTION_STRING) must be set"
+        );
+        assert_eq!(
+            invalid(&[("DATABASE_TLS", "custom")]),
+            "database.tls.ca_file (DATABASE_CA_FILE) must be set when database.tls.mode is custom"
+        );
+        assert_eq!(
+            invalid(&[("DATABASE_CLIENT_CERT", "client.pem")]),
+            "database.tls.client_cert (DATABASE_CLIENT_CERT) and database.tls.client_key (DATABASE_CLIENT_KEY) must be set together"
+        );
+        assert_eq!(
+            invalid(&[("DATABASE_CA_FILE", "/nonexistent/ca.pem")]),
+            "database tls file /nonexistent/ca.pem does not exist"
+        );
+        assert_eq!(
+            invalid(&[("DATABASE_POOL_MAX_SIZE", "0")]),
+            "database.pool.max_size (DATABASE_POOL_MAX_SIZE) must be greater than zero"
+        );
+        assert_eq!(
+            invalid(&[
+                ("DATABASE_POOL_MAX_SIZE", "2"),
+                ("DATABASE_POOL_MIN_IDLE", "3")
+            ]),
+            "database.pool.min_idle (DATABASE_POOL_MIN_IDLE) cannot exceed database.pool.max_size"
+        );
+    }
+}
@@ -0,0 +1,173 @@
+use crate::error::Error;
+use axum::extract::ws;
+use serde::{de::DeserializeOwned, Serialize};
+
+// Encodings supported on the wire, negotiated per connection via the `Sec-WebSocket-Protocol` header
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Json,
+    MessagePack,
+    Cbor,
+}
+
+impl Encoding {
+    pub const ALL: [Encoding; 3] = [Encoding::Json, Encoding::MessagePack, Encoding::Cbor];
+
+    // The websocket subprotocol identifying the encoding
+    pub fn protocol(&self) -> &'static str {
+        match self {
+            Encoding::Json => "metafashion.json.v1",
+            Encoding::MessagePack => "metafashion.msgpack.v1",
+            Encoding::Cbor => "metafashion.cbor.v1",
+        }
+    }
+
+    // Selects the first supported encoding from the protocols offered by the client, in order of client preference
+    pub fn negotiate(protocols: &str) -> Option<Encoding> {
+        protocols.split(',').map(str::trim).find_map(|protocol| {
+            Encoding::ALL
+                .into_iter()
+                .find(|encoding| encoding.protocol() == protocol)
+        })
+    }
+
+    // Index of the encoding, used to cache encoded payloads per encoding
+    pub fn index(&self) -> usize {
+        *self as usize
+    }
+
+    pub fn encode<T: Serialize>(&self, value: &T) -> crate::Result<ws::Message> {
+        Ok(match self {
+            Encoding::Json => ws::Message::Text(serde_json::to_string(value)?),
+            // Structs are encoded as maps so that tagged enums remain self-describing
+            Encoding::MessagePack => ws::Message::Binary(rmp_serde::to_vec_named(value)?),
+            Encoding::Cbor => {
+                let mut buffer = Vec::new();
+                ciborium::ser::into_writer(value, &mut buffer)?;
+                ws::Message::Binary(buffer)
+            }
+        })
+    }
+
+    // Decodes a data frame, returning `None` for frames which do not carry data in this encoding
+    pub fn decode<T: DeserializeOwned>(&self, message: &ws::Message) -> Option<crate::Result<T>> {
+        match (self, message) {
+            (Encoding::Json, ws::Message::Text(value)) => {
+                Some(serde_json::from_str(value).map_err(Error::from))
+            }
+            (Encoding::MessagePack, ws::Message::Binary(value)) => {
+                Some(rmp_serde::from_slice(value).map_err(Error::from))
+            }
+            (Encoding::Cbor, ws::Message::Binary(value)) => {
+                Some(ciborium::de::from_reader(value.as_slice()).map_err(Error::from))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hub::{Message, Request, Topic};
+    use crate::models::Status;
+    use crate::pow::Solution;
+    use chrono::{TimeZone, Utc};
+    use primitive_types::H160;
+    use serde_json::Value;
+
+    // Round trips the value through the encoding, comparing the JSON representations as the types are not comparable
+    fn round_trip<T: Serialize + DeserializeOwned>(encoding: Encoding, value: &T) {
+        let encoded = encoding.encode(value).unwrap();
+        assert_eq!(
+            matches!(encoded, ws::Message::Text(_)),
+            encoding == Encoding::Json
+        );
+        let decoded: T = encoding.decode(&encoded).unwrap().unwrap();
+        assert_eq!(
+            serde_json::to_value(&decoded).unwrap(),
+            serde_json::to_value(value).unwrap()
+        );
+    }
+
+    #[test]
+    fn requests_round_trip() {
+        let requests = [
+            Request::SignUp {
+                address: H160::from_low_u64_be(1),
+                solution: Some(Solution {
+                    challenge: "v1.1650000000.20.00".to_string(),
+                    nonce: u64::MAX,
+                }),
+            },
+            Request::Check {
+                address: H160::from_low_u64_be(2),
+            },
+            Request::Challenge,
+            Request::Subscribe {
+                topics: vec![Topic::Stats, Topic::Campaign("vip".to_string())],
+            },
+            Request::Resume { after_seq: 42 },
+        ];
+        for encoding in Encoding::ALL {
+            for request in &requests {
+                round_trip(encoding, request);
+            }
+        }
+    }
+
+    #[test]
+    fn messages_round_trip() {
+        let messages = [
+            Message::SignedUp {
+                total: 42,
+                signed_up: Some(true),
+                last_signed_up: Some(Utc.timestamp(1_650_000_000, 0)),
+                status: Status::Open,
+            },
+            Message::Subscriptions {
+                topics: vec![Topic::Totals, Topic::Announcements],
+            },
+            Message::Removed {
+                address: H160::from_low_u64_be(1),
+                reason: None,
+            },
+        ];
+        for encoding in Encoding::ALL {
+            for message in &messages {
+                round_trip(encoding, message);
+            }
+        }
+    }
+
+    #[test]
+    fn frames_of_another_encoding_are_not_decoded() {
+        let text = Encoding::Json.encode(&Request::Challenge).unwrap();
+        let binary = Encoding::Cbor.encode(&Request::Challenge).unwrap();
+        assert!(Encoding::Cbor.decode::<Value>(&text).is_none());
+        assert!(Encoding::MessagePack.decode::<Value>(&text).is_none());
+        assert!(Encoding::Json.decode::<Value>(&binary).is_none());
+        assert!(Encoding::Json
+            .decode::<Request>(&ws::Message::Text("{\"type\":\"unknown\"}".into()))
+            .unwrap()
+            .is_err());
+    }
+
+    #[test]
+    fn encodings_are_negotiated_in_client_preference() {
+        assert_eq!(
+            Encoding::negotiate("metafashion.cbor.v1, metafashion.msgpack.v1"),
+            Some(Encoding::Cbor)
+        );
+        assert_eq!(
+            Encoding::negotiate("graphql-ws,metafashion.msgpack.v1"),
+            Some(Encoding::MessagePack)
+        );
+        assert_eq!(
+            Encoding::negotiate("metafashion.json.v1"),
+            Some(Encoding::Json)
+        );
+        assert_eq!(Encoding::negotiate("metafashion.cbor.v2, graphql-ws"), None);
+        assert_eq!(Encoding::negotiate(""), None);
+    }
+}
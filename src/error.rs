@@ -1,6 +1,5 @@
 use bb8::RunError;
 use rustc_hex::FromHexError;
-use serde::Serialize;
 use thiserror::Error;
 
 //pub const LOG_TARGET: &str = "api";
@@ -8,26 +7,71 @@ use thiserror::Error;
 #[derive(Error, Debug)]
 pub enum Error {
     #[error("Error getting connection from the pool: {0}")]
-    ConnectionPoolError(#[from] RunError<tokio_postgres::Error>),
+    PoolConnection(#[from] RunError<tokio_postgres::Error>),
     #[error("Error executing database query: {0}")]
-    DatabaseQueryError(#[from] tokio_postgres::Error),
-    #[error("Error initialising the database: {0}")]
-    DatabaseInitialisationError(tokio_postgres::Error),
+    DatabaseQuery(#[from] tokio_postgres::Error),
+    #[error("Invalid configuration: {0}")]
+    InvalidConfiguration(String),
+    #[error("Invalid import: {0}")]
+    InvalidImport(String),
+    #[error("Invalid query: {0}")]
+    InvalidQuery(String),
+    #[error("Invalid raffle: {0}")]
+    InvalidRaffle(String),
+    #[error("Invalid connection string: {0}")]
+    InvalidConnectionString(tokio_postgres::Error),
+    #[error("Invalid connection pool configuration: {0}")]
+    InvalidPoolConfig(String),
+    #[error("Database unavailable: {0}")]
+    DatabaseUnavailable(tokio_postgres::Error),
+    #[error("Invalid database TLS configuration: {0}")]
+    DatabaseTls(String),
+    #[error("Error applying migration {version} ({name}): {source}")]
+    MigrationFailed {
+        version: i64,
+        name: &'static str,
+        source: tokio_postgres::Error,
+    },
+    #[cfg(feature = "sqlite")]
+    #[error("Error executing SQLite query: {0}")]
+    Sqlite(#[from] rusqlite::Error),
     #[error("error reading file: {0}")]
-    ReadFileError(#[from] std::io::Error),
+    ReadFile(#[from] std::io::Error),
     #[error("Error getting connection from the pool: {0}")]
-    SerialisationError(#[from] serde_json::Error),
+    Serialisation(#[from] serde_json::Error),
+    #[error("error encoding message as MessagePack: {0}")]
+    MessagePackEncode(#[from] rmp_serde::encode::Error),
+    #[error("error decoding MessagePack message: {0}")]
+    MessagePackDecode(#[from] rmp_serde::decode::Error),
+    #[error("error encoding message as CBOR: {0}")]
+    CborEncode(#[from] ciborium::ser::Error<std::io::Error>),
+    #[error("error decoding CBOR message: {0}")]
+    CborDecode(#[from] ciborium::de::Error<std::io::Error>),
     #[error("The request was unauthorised")]
-    UnauthorisedError,
+    Unauthorised,
     #[error("error converting from hex: {0}")]
-    HashError(#[from] FromHexError),
+    InvalidHex(#[from] FromHexError),
     #[error("VIP signup closed")]
     VIPSignupClosed,
-}
-
-#[derive(Serialize)]
-struct ErrorResponse {
-    message: String,
+    #[error("address {0:#x} is blocked")]
+    AddressBlocked(primitive_types::H160),
+    #[error("a proof-of-work challenge solution is required to sign up")]
+    ChallengeRequired,
+    #[error("invalid proof-of-work challenge solution: {0}")]
+    InvalidChallenge(&'static str),
+    #[error("invalid topic: {0}")]
+    InvalidTopic(String),
+    #[error("too many connections")]
+    TooManyConnections,
+    #[error("too many connections from {0}")]
+    TooManyConnectionsFromAddress(std::net::IpAddr),
+    #[error("invalid rate limit, expected capacity/seconds: {0}")]
+    InvalidRateLimit(String),
+    #[error("rate limit exceeded per {scope}, retry after {retry_after:?}")]
+    RateLimited {
+        scope: crate::rate_limit::Scope,
+        retry_after: std::time::Duration,
+    },
 }
 
 //impl warp::reject::Reject for Error {}
@@ -44,7 +88,7 @@ struct ErrorResponse {
 //         message = "Invalid Body";
 //     } else if let Some(e) = err.find::<Error>() {
 //         match e {
-//             Error::DatabaseQueryError(_) => {
+//             Error::DatabaseQuery(_) => {
 //                 code = StatusCode::BAD_REQUEST;
 //                 message = "Could not Execute request";
 //             }
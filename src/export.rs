@@ -0,0 +1,469 @@
+use crate::db;
+use crate::models::{AllowlistEntry, AllowlistQuery};
+use clap::ArgEnum;
+use futures::{future, pin_mut, Future, StreamExt};
+use primitive_types::H160;
+use rustc_hex::ToHex;
+use sha2::{Digest, Sha256};
+use std::io::{self, Write};
+use tiny_keccak::{Hasher, Keccak};
+use tokio_postgres::GenericClient;
+
+#[derive(ArgEnum, Copy, Clone, Debug)]
+pub enum Format {
+    Csv,
+    Json,
+    Ndjson,
+    // Solidity library for use within Foundry scripts and tests
+    Solidity,
+}
+
+impl Format {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Format::Csv => "csv",
+            Format::Json => "json",
+            Format::Ndjson => "ndjson",
+            Format::Solidity => "solidity",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Format::Csv => "text/csv; charset=utf-8",
+            Format::Json => "application/json",
+            Format::Ndjson => "application/x-ndjson",
+            Format::Solidity => "text/plain; charset=utf-8",
+        }
+    }
+}
+
+#[derive(ArgEnum, Copy, Clone, Debug, PartialEq)]
+pub enum Field {
+    #[clap(name = "address")]
+    Address,
+    #[clap(name = "signed_up_at")]
+    SignedUpAt,
+    #[clap(name = "tier")]
+    Tier,
+    #[clap(name = "allocation")]
+    Allocation,
+}
+
+impl Field {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Field::Address => "address",
+            Field::SignedUpAt => "signed_up_at",
+            Field::Tier => "tier",
+            Field::Allocation => "allocation",
+        }
+    }
+}
+
+pub struct Summary {
+    pub total: u64,
+    // Hex encoded SHA-256 of the bytes written
+    pub sha256: String,
+}
+
+// Size of the chunks in which output is passed on
+const CHUNK_SIZE: usize = 64 * 1024;
+
+// Writes the allowlist entries matching the query to the writer in the requested format, hashing the output as it is
+// written
+pub async fn write<C: GenericClient, W: Write>(
+    client: &C,
+    query: &AllowlistQuery,
+    format: Format,
+    fields: &[Field],
+    mut writer: W,
+) -> crate::Result<Summary> {
+    let summary = stream(client, query, format, fields, |chunk| {
+        future::ready(writer.write_all(&chunk).map_err(Into::into))
+    })
+    .await?;
+    writer.flush()?;
+    Ok(summary)
+}
+
+// Streams the allowlist entries matching the query in the requested format, passing the output on to the sink in
+// chunks and hashing it as it is produced. Addresses are EIP-55 checksummed so that the output can be used as is
+// within contracts.
+pub async fn stream<C, F, Fut>(
+    client: &C,
+    query: &AllowlistQuery,
+    format: Format,
+    fields: &[Field],
+    mut sink: F,
+) -> crate::Result<Summary>
+where
+    C: GenericClient,
+    F: FnMut(Vec<u8>) -> Fut,
+    Fut: Future<Output = crate::Result<()>>,
+{
+    let mut writer = HashingWriter {
+        inner: Vec::with_capacity(CHUNK_SIZE),
+        hasher: Sha256::new(),
+    };
+
+    match format {
+        Format::Csv => {
+            let header: Vec<&str> = fields.iter().map(Field::name).collect();
+            writeln!(writer, "{}", header.join(","))?;
+        }
+        Format::Json => write!(writer, "[")?,
+        Format::Ndjson => {}
+        Format::Solidity => {
+            // Solidity arrays are sized up front, so count the entries first (expected to be within a transaction)
+            let total = db::vip::allowlist_total(client, query).await?;
+            solidity_header(&mut writer, fields, total)?;
+        }
+    }
+
+    let entries = db::vip::allowlist(client, query).await?;
+    pin_mut!(entries);
+    let mut total = 0;
+    while let Some(entry) = entries.next().await {
+        let entry = entry?;
+        match format {
+            Format::Csv => csv_row(&mut writer, fields, &entry)?,
+            Format::Json => {
+                write!(writer, "{}\n  ", if total == 0 { "" } else { "," })?;
+                json_object(&mut writer, fields, &entry)?;
+            }
+            Format::Ndjson => {
+                json_object(&mut writer, fields, &entry)?;
+                writeln!(writer)?;
+            }
+            Format::Solidity => solidity_entry(&mut writer, fields, &entry, total)?,
+        }
+        total += 1;
+        if writer.inner.len() >= CHUNK_SIZE {
+            sink(std::mem::replace(
+                &mut writer.inner,
+                Vec::with_capacity(CHUNK_SIZE),
+            ))
+            .await?;
+        }
+    }
+
+    match format {
+        Format::Csv | Format::Ndjson => {}
+        Format::Json => writeln!(writer, "{}]", if total == 0 { "" } else { "\n" })?,
+        Format::Solidity => writeln!(writer, "    }}\n}}")?,
+    }
+    if !writer.inner.is_empty() {
+        sink(writer.inner).await?;
+    }
+
+    Ok(Summary {
+        total,
+        sha256: writer.hasher.finalize().to_hex(),
+    })
+}
+
+// Encodes the address with the mixed-case checksum defined by EIP-55
+pub fn checksum(address: &H160) -> String {
+    let address: String = address.as_bytes().to_hex();
+    let mut hash = [0u8; 32];
+    let mut keccak = Keccak::v256();
+    keccak.update(address.as_bytes());
+    keccak.finalize(&mut hash);
+
+    let mut checksummed = String::with_capacity(42);
+    checksummed.push_str("0x");
+    for (i, c) in address.chars().enumerate() {
+        let nibble = if i % 2 == 0 {
+            hash[i / 2] >> 4
+        } else {
+            hash[i / 2] & 0x0f
+        };
+        checksummed.push(if nibble >= 8 {
+            c.to_ascii_uppercase()
+        } else {
+            c
+        });
+    }
+    checksummed
+}
+
+fn csv_row(writer: &mut impl Write, fields: &[Field], entry: &AllowlistEntry) -> io::Result<()> {
+    let values: Vec<String> = fields
+        .iter()
+        .map(|field| {
+            csv_field(&match field {
+                Field::Address => checksum(&entry.address),
+                Field::SignedUpAt => entry.signed_up_at.to_rfc3339(),
+                Field::Tier => entry.tier.clone().unwrap_or_default(),
+                Field::Allocation => entry.allocation.to_string(),
+            })
+        })
+        .collect();
+    writeln!(writer, "{}", values.join(","))
+}
+
+// Quotes the value as a CSV field when it contains a delimiter, quote or line break
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+// Writes the entry as a JSON object with its fields in the requested order
+fn json_object(
+    writer: &mut impl Write,
+    fields: &[Field],
+    entry: &AllowlistEntry,
+) -> crate::Result<()> {
+    write!(writer, "{{")?;
+    for (i, field) in fields.iter().enumerate() {
+        let value = match field {
+            Field::Address => serde_json::to_string(&checksum(&entry.address))?,
+            Field::SignedUpAt => serde_json::to_string(&entry.signed_up_at)?,
+            Field::Tier => serde_json::to_string(&entry.tier)?,
+            Field::Allocation => entry.allocation.to_string(),
+        };
+        write!(
+            writer,
+            "{}\"{}\":{}",
+            if i == 0 { "" } else { "," },
+            field.name(),
+            value
+        )?;
+    }
+    write!(writer, "}}")?;
+    Ok(())
+}
+
+fn solidity_header(writer: &mut impl Write, fields: &[Field], total: u64) -> io::Result<()> {
+    writeln!(writer, "// SPDX-License-Identifier: UNLICENSED")?;
+    writeln!(writer, "pragma solidity ^0.8.0;")?;
+    writeln!(writer)?;
+    writeln!(
+        writer,
+        "// Allowlist of {} entries, generated by metafashion-api",
+        total
+    )?;
+    writeln!(writer, "library Allowlist {{")?;
+    // A plain address array is simplest to consume when only addresses are exported
+    if fields == [Field::Address] {
+        writeln!(
+            writer,
+            "    function addresses() internal pure returns (address[] memory list) {{"
+        )?;
+        return writeln!(writer, "        list = new address[]({});", total);
+    }
+
+    writeln!(writer, "    struct Entry {{")?;
+    for field in fields {
+        writeln!(
+            writer,
+            "        {};",
+            match field {
+                Field::Address => "address account",
+                Field::SignedUpAt => "uint64 signedUpAt",
+                Field::Tier => "string tier",
+                Field::Allocation => "uint256 allocation",
+            }
+        )?;
+    }
+    writeln!(writer, "    }}")?;
+    writeln!(writer)?;
+    writeln!(
+        writer,
+        "    function entries() internal pure returns (Entry[] memory list) {{"
+    )?;
+    writeln!(writer, "        list = new Entry[]({});", total)
+}
+
+fn solidity_entry(
+    writer: &mut impl Write,
+    fields: &[Field],
+    entry: &AllowlistEntry,
+    index: u64,
+) -> io::Result<()> {
+    let values: Vec<String> = fields
+        .iter()
+        .map(|field| match field {
+            Field::Address => checksum(&entry.address),
+            Field::SignedUpAt => entry.signed_up_at.timestamp().to_string(),
+            Field::Tier => solidity_string(entry.tier.as_deref().unwrap_or_default()),
+            Field::Allocation => entry.allocation.to_string(),
+        })
+        .collect();
+    if fields == [Field::Address] {
+        writeln!(writer, "        list[{}] = {};", index, values[0])
+    } else {
+        writeln!(
+            writer,
+            "        list[{}] = Entry({});",
+            index,
+            values.join(", ")
+        )
+    }
+}
+
+// Quotes the value as a Solidity string literal, escaping anything other than printable ASCII
+fn solidity_string(value: &str) -> String {
+    let mut literal = String::with_capacity(value.len() + 2);
+    literal.push('"');
+    for byte in value.bytes() {
+        match byte {
+            b'"' | b'\\' => {
+                literal.push('\\');
+                literal.push(byte as char);
+            }
+            0x20..=0x7e => literal.push(byte as char),
+            _ => literal.push_str(&format!("\\x{:02x}", byte)),
+        }
+    }
+    literal.push('"');
+    literal
+}
+
+// Hashes everything written through to the inner writer
+struct HashingWriter<W> {
+    inner: W,
+    hasher: Sha256,
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{TimeZone, Utc};
+    use std::str::FromStr;
+
+    fn entry(address: &str, tier: Option<&str>) -> AllowlistEntry {
+        AllowlistEntry {
+            address: H160::from_str(address).unwrap(),
+            signed_up_at: Utc.timestamp(1_650_000_000, 0),
+            tier: tier.map(str::to_string),
+            allocation: 2,
+            campaign: String::new(),
+        }
+    }
+
+    #[test]
+    fn addresses_are_checksummed() {
+        // Test vectors from EIP-55
+        for expected in [
+            "0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed",
+            "0xfB6916095ca1df60bB79Ce92cE3Ea74c37c5d359",
+            "0xdbF03B407c01E7cD3CBea99509d93f8DDDC8C6FB",
+            "0xD1220A0cf47c7B9Be7A2E6BA89F429762e7b9aDb",
+        ] {
+            let address = H160::from_str(&expected[2..].to_lowercase()).unwrap();
+            assert_eq!(checksum(&address), expected);
+        }
+    }
+
+    #[test]
+    fn csv_fields_are_escaped() {
+        assert_eq!(csv_field("gold"), "gold");
+        assert_eq!(csv_field("gold, silver"), "\"gold, silver\"");
+        assert_eq!(csv_field("\"gold\""), "\"\"\"gold\"\"\"");
+        assert_eq!(csv_field("gold\r\nsilver"), "\"gold\r\nsilver\"");
+
+        let mut output = Vec::new();
+        let fields = [Field::Address, Field::Tier, Field::Allocation];
+        let entry = entry("5aaeb6053f3e94c9b9a09f33669435e7ef1beaed", Some("a,\"b\""));
+        csv_row(&mut output, &fields, &entry).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed,\"a,\"\"b\"\"\",2\n"
+        );
+    }
+
+    #[test]
+    fn json_objects_follow_the_field_order() {
+        let mut output = Vec::new();
+        let fields = [Field::Tier, Field::SignedUpAt, Field::Address];
+        let entry = entry("5aaeb6053f3e94c9b9a09f33669435e7ef1beaed", None);
+        json_object(&mut output, &fields, &entry).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "{\"tier\":null,\"signed_up_at\":\"2022-04-15T05:20:00Z\",\
+            \"address\":\"0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed\"}"
+        );
+    }
+
+    #[test]
+    fn addresses_are_rendered_as_a_solidity_array() {
+        let mut output = Vec::new();
+        let fields = [Field::Address];
+        solidity_header(&mut output, &fields, 2).unwrap();
+        for (i, address) in [
+            "5aaeb6053f3e94c9b9a09f33669435e7ef1beaed",
+            "fb6916095ca1df60bb79ce92ce3ea74c37c5d359",
+        ]
+        .iter()
+        .enumerate()
+        {
+            solidity_entry(&mut output, &fields, &entry(address, None), i as u64).unwrap();
+        }
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "// SPDX-License-Identifier: UNLICENSED
+pragma solidity ^0.8.0;
+
+// Allowlist of 2 entries, generated by metafashion-api
+library Allowlist {
+    function addresses() internal pure returns (address[] memory list) {
+        list = new address[](2);
+        list[0] = 0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed;
+        list[1] = 0xfB6916095ca1df60bB79Ce92cE3Ea74c37c5d359;
+"
+        );
+    }
+
+    #[test]
+    fn entries_are_rendered_as_solidity_structs() {
+        let mut output = Vec::new();
+        let fields = [
+            Field::Address,
+            Field::SignedUpAt,
+            Field::Tier,
+            Field::Allocation,
+        ];
+        solidity_header(&mut output, &fields, 1).unwrap();
+        let entry = entry(
+            "5aaeb6053f3e94c9b9a09f33669435e7ef1beaed",
+            Some("\"gold\"\\é"),
+        );
+        solidity_entry(&mut output, &fields, &entry, 0).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "// SPDX-License-Identifier: UNLICENSED
+pragma solidity ^0.8.0;
+
+// Allowlist of 1 entries, generated by metafashion-api
+library Allowlist {
+    struct Entry {
+        address account;
+        uint64 signedUpAt;
+        string tier;
+        uint256 allocation;
+    }
+
+    function entries() internal pure returns (Entry[] memory list) {
+        list = new Entry[](1);
+        list[0] = Entry(0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed, 1650000000, \"\\\"gold\\\"\\\\\\xc3\\xa9\", 2);
+"
+        );
+    }
+}
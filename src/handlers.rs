@@ -1,35 +1,618 @@
-use crate::{db, error, Hub};
-use axum::extract::{TypedHeader, WebSocketUpgrade};
+use crate::admission::Admission;
+use crate::encoding::Encoding;
+use crate::models::{Actor, SignUpMetadata};
+use crate::store::SignUpStore;
+use crate::{error, hub, Hub};
+use axum::extract::{ConnectInfo, OriginalUri, Query, TypedHeader, WebSocketUpgrade};
 use axum::{
     async_trait,
     extract::{Extension, FromRequest, RequestParts},
-    http::StatusCode,
-    response::{IntoResponse, Response},
+    http::{header, HeaderMap, StatusCode},
+    response::{Headers, IntoResponse, Response},
     Json,
 };
+use hmac::{Hmac, Mac};
+use rustc_hex::ToHex;
+use serde::Deserialize;
 use serde_json::json;
+use sha2::Sha256;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
+use subtle::ConstantTimeEq;
 
-pub struct Connection(db::Connection);
+const API_KEY_HEADER: &str = "x-api-key";
+// Optionally names the operator using the admin API, for the audit log
+const ACTOR_HEADER: &str = "x-actor";
+// Maximum lengths of recorded metadata, matching the columns
+const MAX_USER_AGENT_LENGTH: usize = 512;
+const MAX_METADATA_LENGTH: usize = 255;
 
-pub async fn health(Connection(connection): Connection) -> crate::Result<StatusCode> {
-    db::healthy(&connection).await?;
+// Key required to access the admin API
+#[derive(Clone)]
+pub struct AdminApiKey(pub String);
+
+// Requires the request to be authorised using the admin API key, identifying the actor for the audit log
+pub struct Admin(pub Actor);
+
+// Salt with which client IPs are hashed, present only when sign-up metadata is recorded
+#[derive(Clone)]
+pub struct MetadataSalt(pub String);
+
+// UTM parameters, accepted on any request which may result in a sign-up
+#[derive(Deserialize, Default)]
+pub struct UtmParams {
+    utm_source: Option<String>,
+    utm_medium: Option<String>,
+    utm_campaign: Option<String>,
+    utm_term: Option<String>,
+    utm_content: Option<String>,
+}
+
+pub async fn health(
+    Extension(store): Extension<Arc<dyn SignUpStore>>,
+) -> crate::Result<StatusCode> {
+    store.healthy().await?;
     Ok(StatusCode::OK)
 }
 
+#[derive(Deserialize)]
+pub struct WebsocketParams {
+    version: Option<u32>,
+    #[serde(flatten)]
+    utm: UtmParams,
+}
+
+#[allow(clippy::too_many_arguments)]
 pub async fn websocket(
     ws: WebSocketUpgrade,
     user_agent: Option<TypedHeader<headers::UserAgent>>,
+    headers: HeaderMap,
+    Query(params): Query<WebsocketParams>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
     Extension(hub): Extension<Arc<Hub>>,
-) -> impl IntoResponse {
-    if let Some(TypedHeader(user_agent)) = user_agent {
+    Extension(admission): Extension<Arc<Admission>>,
+    salt: Option<Extension<MetadataSalt>>,
+) -> Response {
+    if let Some(TypedHeader(user_agent)) = &user_agent {
         tracing::debug!("`{}` connected", user_agent.as_str());
     }
+
+    // Stop accepting connections once shutting down, asking clients to retry after reconnect delay
+    if let Some(reconnect_in) = hub.shutting_down() {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Headers([(header::RETRY_AFTER, reconnect_in.as_secs().to_string())]),
+            "server restarting",
+        )
+            .into_response();
+    }
+
+    // Negotiate protocol version, rejecting versions which are no longer supported
+    let version = match hub::negotiate_version(params.version) {
+        Some(version) => version,
+        None => {
+            return (
+                StatusCode::BAD_REQUEST,
+                format!(
+                    "protocol version not supported, minimum version is {}",
+                    hub::MIN_PROTOCOL_VERSION
+                ),
+            )
+                .into_response()
+        }
+    };
+
+    // Negotiate encoding from requested subprotocols, defaulting to JSON when none requested/supported
+    let encoding = headers
+        .get(header::SEC_WEBSOCKET_PROTOCOL)
+        .and_then(|protocols| protocols.to_str().ok())
+        .and_then(Encoding::negotiate);
+    let ws = match encoding {
+        Some(encoding) => ws.protocols([encoding.protocol()]),
+        None => ws,
+    };
+    let encoding = encoding.unwrap_or(Encoding::Json);
+
+    // Admit connection, holding permit until disconnected
+    let ip = admission.client_ip(peer, &headers);
+    let permit = match admission.admit(ip) {
+        Ok(permit) => permit,
+        Err(e) => {
+            tracing::warn!("connection from {} rejected: {}", ip, e);
+            return e.into_response();
+        }
+    };
+
+    // Capture metadata to be recorded with any sign-up over the connection, when enabled
+    let metadata = salt.map(|Extension(salt)| {
+        let user_agent = user_agent
+            .as_ref()
+            .map(|TypedHeader(user_agent)| user_agent);
+        metadata(&salt, ip, user_agent, &headers, &params.utm)
+    });
+
     ws.on_upgrade(move |socket| async move {
-        hub.connect(socket).await;
+        hub.connect(socket, encoding, version, ip, metadata).await;
+        drop(permit);
     })
 }
 
+// Captures the metadata of a request for recording with a sign-up, hashing the client IP with the salt
+pub fn metadata(
+    salt: &MetadataSalt,
+    ip: IpAddr,
+    user_agent: Option<&headers::UserAgent>,
+    headers: &HeaderMap,
+    utm: &UtmParams,
+) -> SignUpMetadata {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(salt.0.as_bytes()).expect("hmac accepts keys of any length");
+    mac.update(ip.to_string().as_bytes());
+    let truncate = |value: Option<&str>| {
+        value
+            .map(str::trim)
+            .filter(|value| !value.is_empty())
+            .map(|value| value.chars().take(MAX_METADATA_LENGTH).collect())
+    };
+    SignUpMetadata {
+        ip_hash: Some(mac.finalize().into_bytes().to_hex()),
+        user_agent: user_agent.map(|user_agent| {
+            user_agent
+                .as_str()
+                .chars()
+                .take(MAX_USER_AGENT_LENGTH)
+                .collect()
+        }),
+        origin: truncate(
+            headers
+                .get(header::ORIGIN)
+                .and_then(|origin| origin.to_str().ok()),
+        ),
+        utm_source: truncate(utm.utm_source.as_deref()),
+        utm_medium: truncate(utm.utm_medium.as_deref()),
+        utm_campaign: truncate(utm.utm_campaign.as_deref()),
+        utm_term: truncate(utm.utm_term.as_deref()),
+        utm_content: truncate(utm.utm_content.as_deref()),
+        connection_id: None,
+    }
+}
+
+pub mod admin {
+    use crate::admission::{Admission, Usage};
+    use crate::error::Error::{self, InvalidQuery};
+    use crate::handlers::Admin;
+    use crate::hub::Message;
+    use crate::import::{self, ImportReport};
+    use crate::models::{
+        parse_address, AllowlistOrder, AllowlistQuery, AuditEntry, AuditQuery, Cluster,
+        FingerprintField, Removal, SeriesQuery, SignUpPage, SignUpSearch, SignUpSeries,
+    };
+    use crate::{db, export, Hub};
+    use axum::body::StreamBody;
+    use axum::extract::{Extension, Query};
+    use axum::http::{header, HeaderMap, StatusCode};
+    use axum::response::{Headers, IntoResponse, Response};
+    use axum::Json;
+    use chrono::{DateTime, Utc};
+    use clap::ArgEnum;
+    use primitive_types::H160;
+    use rand::RngCore;
+    use rustc_hex::ToHex;
+    use serde::Deserialize;
+    use serde_json::json;
+    use std::io;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::sync::mpsc;
+    use tokio_postgres::IsolationLevel;
+
+    // Maximum number of clusters returned
+    const MAX_CLUSTERS: u64 = 1000;
+    // Maximum number of sign-ups removed by a request
+    const MAX_REMOVALS: usize = 1000;
+    // Maximum length of an announcement, in characters
+    const MAX_ANNOUNCEMENT_LENGTH: usize = 1000;
+    // Number of chunks of an export buffered whilst awaiting the client
+    const EXPORT_CHUNKS: usize = 4;
+    // Identifies the export, whose audit entry (action `allowlist.export`, targeting the id) records its SHA-256
+    const EXPORT_ID_HEADER: &str = "x-export-id";
+    // Maximum number of buckets within a series, and the number returned when no start is specified
+    const MAX_BUCKETS: i32 = 10_000;
+    const DEFAULT_BUCKETS: i32 = 60;
+
+    pub async fn connections(
+        _: Admin,
+        Extension(admission): Extension<Arc<Admission>>,
+    ) -> Json<Usage> {
+        Json(admission.usage())
+    }
+
+    #[derive(Deserialize)]
+    pub struct ImportParams {
+        #[serde(default)]
+        dry_run: bool,
+    }
+
+    // Signs up the addresses within the CSV or JSON (by content type) body, returning a report of every row
+    pub async fn import(
+        Admin(actor): Admin,
+        Query(params): Query<ImportParams>,
+        headers: HeaderMap,
+        Extension(pool): Extension<db::ConnectionPool>,
+        Extension(hub): Extension<Arc<Hub>>,
+        body: String,
+    ) -> crate::Result<Json<ImportReport>> {
+        let json = headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok())
+            .is_some_and(|content_type| content_type.starts_with("application/json"));
+        let format = match json {
+            true => import::Format::Json,
+            false => import::Format::Csv,
+        };
+        let rows = import::parse(format, &body)?;
+
+        let mut connection = pool.get_connection().await?;
+        let report = import::import(&mut connection, rows, params.dry_run, &actor).await?;
+        tracing::info!(
+            "import of {} rows{}: {} accepted, {} duplicate, {} invalid",
+            report.rows.len(),
+            if params.dry_run { " (dry run)" } else { "" },
+            report.accepted,
+            report.duplicate,
+            report.invalid
+        );
+        if report.accepted > 0 && !params.dry_run {
+            if let Err(e) = hub.announce().await {
+                tracing::warn!("unable to announce updated totals to clients: {}", e);
+            }
+        }
+        Ok(Json(report))
+    }
+
+    #[derive(Deserialize)]
+    pub struct ClustersParams {
+        // Seconds within which consecutive sign-ups sharing a fingerprint are clustered
+        #[serde(default = "default_window")]
+        window: u64,
+        #[serde(default = "default_min_size")]
+        min_size: u64,
+        // Comma-separated fields compared, defaulting to the IP and user agent
+        fingerprint: Option<String>,
+        limit: Option<u64>,
+    }
+
+    fn default_window() -> u64 {
+        3600
+    }
+
+    fn default_min_size() -> u64 {
+        2
+    }
+
+    // Reports clusters of sign-ups sharing a fingerprint within the window, largest first
+    pub async fn clusters(
+        _: Admin,
+        Query(params): Query<ClustersParams>,
+        Extension(pool): Extension<db::ConnectionPool>,
+    ) -> crate::Result<Json<Vec<Cluster>>> {
+        let fields = match &params.fingerprint {
+            Some(fingerprint) => fingerprint
+                .split(',')
+                .map(|field| {
+                    FingerprintField::from_str(field.trim(), true)
+                        .map_err(|_| InvalidQuery(format!("unknown fingerprint field {}", field)))
+                })
+                .collect::<crate::Result<Vec<_>>>()?,
+            None => vec![FingerprintField::Ip, FingerprintField::UserAgent],
+        };
+        if fields.is_empty() || params.window == 0 || params.min_size < 2 {
+            return Err(InvalidQuery(
+                "expected fingerprint fields, a window greater than zero and a min_size of at least 2"
+                    .into(),
+            ));
+        }
+
+        let connection = pool.get_connection().await?;
+        let clusters = db::metadata::clusters(
+            &*connection,
+            &fields,
+            Duration::from_secs(params.window),
+            params.min_size,
+            params.limit.unwrap_or(MAX_CLUSTERS).min(MAX_CLUSTERS),
+        )
+        .await?;
+        Ok(Json(clusters))
+    }
+
+    // Returns a page of the sign-ups matching the search, along with the total matching
+    pub async fn sign_ups(
+        _: Admin,
+        Query(search): Query<SignUpSearch>,
+        Extension(pool): Extension<db::ConnectionPool>,
+    ) -> crate::Result<Json<SignUpPage>> {
+        let mut connection = pool.get_connection().await?;
+        // Read within a single snapshot so that the total is consistent with the page
+        let transaction = connection
+            .build_transaction()
+            .isolation_level(IsolationLevel::RepeatableRead)
+            .read_only(true)
+            .start()
+            .await
+            .map_err(Error::DatabaseQuery)?;
+        let page = db::vip::search(&transaction, &search).await?;
+        transaction.commit().await.map_err(Error::DatabaseQuery)?;
+        Ok(Json(page))
+    }
+
+    #[derive(Deserialize)]
+    pub struct ExportParams {
+        format: Option<String>,
+        // Comma-separated fields to include, in order, defaulting to the address and sign-up time
+        fields: Option<String>,
+        #[serde(default = "default_order")]
+        order: AllowlistOrder,
+        #[serde(default)]
+        descending: bool,
+        from: Option<DateTime<Utc>>,
+        to: Option<DateTime<Utc>>,
+    }
+
+    fn default_order() -> AllowlistOrder {
+        AllowlistOrder::SignedUpAt
+    }
+
+    // Streams the allowlist in the requested format, read within a single snapshot as with the export command. The
+    // hash is only known once the body has been sent, so the export is identified by the `x-export-id` header and its
+    // total and SHA-256 recorded in the audit log once complete, queryable with `/audit?target={id}`.
+    pub async fn export(
+        Admin(actor): Admin,
+        Query(params): Query<ExportParams>,
+        Extension(pool): Extension<db::ConnectionPool>,
+    ) -> crate::Result<Response> {
+        let format = match &params.format {
+            Some(format) => export::Format::from_str(format.trim(), true)
+                .map_err(|_| InvalidQuery(format!("unknown format {}", format)))?,
+            None => export::Format::Csv,
+        };
+        let fields = match &params.fields {
+            Some(fields) => fields
+                .split(',')
+                .map(|field| {
+                    export::Field::from_str(field.trim(), true)
+                        .map_err(|_| InvalidQuery(format!("unknown field {}", field)))
+                })
+                .collect::<crate::Result<Vec<_>>>()?,
+            None => vec![export::Field::Address, export::Field::SignedUpAt],
+        };
+        let query = AllowlistQuery {
+            from: params.from,
+            to: params.to,
+            order: params.order,
+            descending: params.descending,
+        };
+        let mut id = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut id);
+        let id: String = id.to_hex();
+
+        // Stream the output as it is produced, aborting the response should the export fail part way through
+        let mut connection = pool.get_connection().await?;
+        let (tx, mut rx) = mpsc::channel::<io::Result<Vec<u8>>>(EXPORT_CHUNKS);
+        let export_id = id.clone();
+        tokio::spawn(async move {
+            let exported = async {
+                let transaction = connection
+                    .build_transaction()
+                    .isolation_level(IsolationLevel::RepeatableRead)
+                    .read_only(true)
+                    .start()
+                    .await
+                    .map_err(Error::DatabaseQuery)?;
+                let summary = export::stream(&transaction, &query, format, &fields, |chunk| {
+                    let tx = tx.clone();
+                    async move {
+                        tx.send(Ok(chunk)).await.map_err(|_| {
+                            io::Error::new(io::ErrorKind::BrokenPipe, "client disconnected").into()
+                        })
+                    }
+                })
+                .await?;
+                transaction.commit().await.map_err(Error::DatabaseQuery)?;
+                Ok::<_, Error>(summary)
+            };
+            match exported.await {
+                Ok(summary) => {
+                    tracing::info!(
+                        "{} exported {} sign-ups as {}, sha256 {}",
+                        actor.name,
+                        summary.total,
+                        export_id,
+                        summary.sha256
+                    );
+                    let recorded = db::audit::record(
+                        &*connection,
+                        &actor,
+                        "allowlist.export",
+                        Some(&export_id),
+                        None,
+                        Some(json!({
+                            "format": format.name(),
+                            "fields": fields.iter().map(export::Field::name).collect::<Vec<_>>(),
+                            "order": query.order,
+                            "descending": query.descending,
+                            "from": query.from,
+                            "to": query.to,
+                            "total": summary.total,
+                            "sha256": summary.sha256,
+                        })),
+                    )
+                    .await;
+                    if let Err(e) = recorded {
+                        tracing::warn!("unable to record export {}: {}", export_id, e);
+                    }
+                }
+                Err(e) => {
+                    tracing::warn!("export by {} failed: {}", actor.name, e);
+                    let _ = tx.send(Err(io::Error::other(e.to_string()))).await;
+                }
+            }
+        });
+
+        let body = StreamBody::new(futures::stream::poll_fn(move |cx| rx.poll_recv(cx)));
+        Ok((
+            Headers([
+                (
+                    header::CONTENT_TYPE.as_str(),
+                    format.content_type().to_string(),
+                ),
+                (EXPORT_ID_HEADER, id),
+            ]),
+            body,
+        )
+            .into_response())
+    }
+
+    // Returns sign-up counts in buckets over the range, with cumulative totals and per-campaign counts
+    pub async fn stats(
+        _: Admin,
+        Query(query): Query<SeriesQuery>,
+        Extension(pool): Extension<db::ConnectionPool>,
+    ) -> crate::Result<Json<SignUpSeries>> {
+        let bucket = query.bucket;
+        let to = query.to.unwrap_or_else(Utc::now);
+        let from = match query.from {
+            Some(from) => from,
+            None => to
+                .checked_sub_signed(bucket.duration() * DEFAULT_BUCKETS)
+                .ok_or_else(|| InvalidQuery(format!("time {} out of range", to)))?,
+        };
+        // Align the start to its bucket before limiting the range, as the series is counted from there
+        let from = db::stats::start(bucket, from)?;
+        if from >= to {
+            return Err(InvalidQuery("expected from to be before to".into()));
+        }
+        if to - from > bucket.duration() * MAX_BUCKETS {
+            return Err(InvalidQuery(format!(
+                "at most {} buckets may be requested",
+                MAX_BUCKETS
+            )));
+        }
+
+        let mut connection = pool.get_connection().await?;
+        // Read within a single snapshot so that the cumulative totals are consistent with the buckets
+        let transaction = connection
+            .build_transaction()
+            .isolation_level(IsolationLevel::RepeatableRead)
+            .read_only(true)
+            .start()
+            .await
+            .map_err(Error::DatabaseQuery)?;
+        let series =
+            db::stats::series(&transaction, bucket, from, to, query.campaign.as_deref()).await?;
+        transaction.commit().await.map_err(Error::DatabaseQuery)?;
+        Ok(Json(series))
+    }
+
+    #[derive(Deserialize)]
+    pub struct RemoveRequest {
+        addresses: Vec<H160>,
+        reason: Option<String>,
+        // Also blocks the addresses, so that they cannot sign up again
+        #[serde(default)]
+        block: bool,
+    }
+
+    // Removes the sign-ups of the addresses, broadcasting the updated total and notifying the removed wallets
+    pub async fn remove(
+        Admin(actor): Admin,
+        Extension(pool): Extension<db::ConnectionPool>,
+        Extension(hub): Extension<Arc<Hub>>,
+        Json(request): Json<RemoveRequest>,
+    ) -> crate::Result<Json<Removal>> {
+        if request.addresses.is_empty() || request.addresses.len() > MAX_REMOVALS {
+            return Err(InvalidQuery(format!(
+                "expected between 1 and {} addresses",
+                MAX_REMOVALS
+            )));
+        }
+        let reason = request.reason.as_deref();
+        let block = request.block.then_some(db::blocklist::REMOVAL_SOURCE);
+
+        let mut connection = pool.get_connection().await?;
+        let removal =
+            db::vip::remove(&mut connection, &request.addresses, reason, block, &actor).await?;
+        tracing::info!(
+            "{} removed {} sign-ups ({} not found), blocking {}",
+            actor.name,
+            removal.removed.len(),
+            removal.not_found.len(),
+            removal.blocked
+        );
+        if !removal.removed.is_empty() {
+            if let Err(e) = hub.removed(&removal.removed, reason).await {
+                tracing::warn!("unable to notify clients of removed sign-ups: {}", e);
+            }
+        }
+        Ok(Json(removal))
+    }
+
+    #[derive(Deserialize)]
+    pub struct AnnounceRequest {
+        message: String,
+    }
+
+    // Broadcasts the announcement to clients subscribed to announcements, recording it in the audit log when running
+    // with a database
+    pub async fn announce(
+        Admin(actor): Admin,
+        pool: Option<Extension<db::ConnectionPool>>,
+        Extension(hub): Extension<Arc<Hub>>,
+        Json(request): Json<AnnounceRequest>,
+    ) -> crate::Result<StatusCode> {
+        let message = request.message.trim();
+        if message.is_empty() || message.chars().count() > MAX_ANNOUNCEMENT_LENGTH {
+            return Err(InvalidQuery(format!(
+                "expected a message of between 1 and {} characters",
+                MAX_ANNOUNCEMENT_LENGTH
+            )));
+        }
+
+        if let Some(Extension(pool)) = pool {
+            let connection = pool.get_connection().await?;
+            db::audit::record(
+                &*connection,
+                &actor,
+                "hub.announce",
+                None,
+                None,
+                Some(json!({ "message": message })),
+            )
+            .await?;
+        }
+        tracing::info!("{} announced: {}", actor.name, message);
+        hub.broadcast(Message::Announcement {
+            message: message.to_string(),
+            announced_at: Utc::now(),
+        })
+        .await?;
+        Ok(StatusCode::ACCEPTED)
+    }
+
+    // Returns audit entries matching the filters, most recent first
+    pub async fn audit(
+        _: Admin,
+        Query(mut query): Query<AuditQuery>,
+        Extension(pool): Extension<db::ConnectionPool>,
+    ) -> crate::Result<Json<Vec<AuditEntry>>> {
+        // Addresses are recorded as lowercase hex, without the prefix
+        if let Some(address) = query.target.as_deref().and_then(|t| parse_address(t).ok()) {
+            query.target = Some(format!("{:x}", address));
+        }
+        let connection = pool.get_connection().await?;
+        Ok(Json(db::audit::query(&*connection, &query).await?))
+    }
+}
+
 // pub mod vip {
 //     use crate::handlers::Connection;
 //     use crate::models::SignUps;
@@ -80,43 +663,91 @@ pub async fn websocket(
 //     }
 // }
 
-// Extract database connection
+// Extract admin authorisation
 #[async_trait]
-impl<B> FromRequest<B> for Connection
+impl<B> FromRequest<B> for Admin
 where
     B: Send,
 {
-    type Rejection = (StatusCode, String);
+    type Rejection = error::Error;
 
     async fn from_request(req: &mut RequestParts<B>) -> std::result::Result<Self, Self::Rejection> {
-        let Extension(pool) = Extension::<db::ConnectionPool>::from_request(req)
+        let Extension(AdminApiKey(api_key)) = Extension::<AdminApiKey>::from_request(req)
             .await
-            .map_err(internal_error)?;
+            .map_err(|_| error::Error::Unauthorised)?;
 
-        let connection = pool.get_connection().await.map_err(internal_error)?;
+        let headers = req.headers().ok_or(error::Error::Unauthorised)?;
+        let authorised = headers
+            .get(API_KEY_HEADER)
+            .is_some_and(|value| bool::from(value.as_bytes().ct_eq(api_key.as_bytes())));
+        if !authorised {
+            return Err(error::Error::Unauthorised);
+        }
 
-        Ok(Self(connection))
+        let header = |name| headers.get(name).and_then(|value| value.to_str().ok());
+        let name = match header(ACTOR_HEADER).map(str::trim) {
+            Some(actor) if !actor.is_empty() => format!("admin:{}", actor),
+            _ => "admin".to_string(),
+        };
+        let extensions = req.extensions();
+        let ip = extensions
+            .and_then(|extensions| {
+                let ConnectInfo(peer) = extensions.get::<ConnectInfo<SocketAddr>>()?;
+                let admission = extensions.get::<Arc<Admission>>()?;
+                Some(admission.client_ip(*peer, headers))
+            })
+            .map(|ip| ip.to_string());
+        // The path including any prefix under which the admin API is nested
+        let path = extensions
+            .and_then(|extensions| extensions.get::<OriginalUri>())
+            .map_or(req.uri().path(), |OriginalUri(uri)| uri.path());
+        let metadata = json!({
+            "ip": ip,
+            "user_agent": header(header::USER_AGENT.as_str()),
+            "method": req.method().as_str(),
+            "path": path,
+        });
+        Ok(Admin(Actor { name, metadata }))
     }
 }
 
-/// Utility function for mapping any error into a `500 Internal Server Error`
-/// response.
-fn internal_error<E>(err: E) -> (StatusCode, String)
-where
-    E: std::error::Error,
-{
-    (StatusCode::INTERNAL_SERVER_ERROR, err.to_string())
-}
-
 impl IntoResponse for error::Error {
     fn into_response(self) -> Response {
-        let (status, error_message) = match self {
-            _ => (StatusCode::INTERNAL_SERVER_ERROR, "internal server error"),
+        let (status, error_message, detail) = match self {
+            error::Error::Unauthorised => (StatusCode::UNAUTHORIZED, "unauthorised", None),
+            // Explain why the request was invalid, so that it can be corrected
+            error::Error::InvalidImport(detail) => {
+                (StatusCode::BAD_REQUEST, "invalid import", Some(detail))
+            }
+            error::Error::InvalidQuery(detail) => {
+                (StatusCode::BAD_REQUEST, "invalid query", Some(detail))
+            }
+            error::Error::TooManyConnections => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "too many connections",
+                None,
+            ),
+            error::Error::TooManyConnectionsFromAddress(_) => (
+                StatusCode::TOO_MANY_REQUESTS,
+                "too many connections from address",
+                None,
+            ),
+            _ => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "internal server error",
+                None,
+            ),
         };
 
-        let body = Json(json!({
-            "error": error_message,
-        }));
+        let body = match detail {
+            Some(detail) => Json(json!({
+                "error": error_message,
+                "detail": detail,
+            })),
+            None => Json(json!({
+                "error": error_message,
+            })),
+        };
 
         (status, body).into_response()
     }
@@ -1,151 +1,572 @@
+use crate::encoding::Encoding;
 use crate::error::Error;
-use crate::models::Status;
+use crate::models::{CurrentBucket, SignUpMetadata, SignUpOutcome, SignUps, Status};
+use crate::pow::{ProofOfWork, Solution};
+use crate::rate_limit::{ConnectionBuckets, RateLimiter};
+use crate::store::SignUpStore;
 use crate::{db, error};
-use axum::extract::ws::WebSocket;
+use axum::extract::ws::{self, CloseFrame, WebSocket};
 use chrono::{DateTime, Utc};
 use futures::stream::SplitStream;
 use futures::{sink::SinkExt, stream::StreamExt};
 use primitive_types::H160;
-use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
-use tokio::sync::{broadcast, mpsc};
+use serde::{Deserialize, Serialize, Serializer};
+use std::collections::{HashSet, VecDeque};
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+use std::time::Duration;
+use subtle::ConstantTimeEq;
+use tokio::sync::{broadcast, mpsc, watch};
+use tokio::task::JoinHandle;
+use tokio::time::{Instant, MissedTickBehavior};
 
 static NEXT_USERID: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(1);
 type Clients = tokio::sync::RwLock<HashSet<usize>>;
+type Subscriptions = Arc<RwLock<HashSet<Topic>>>;
+type Wallets = Arc<RwLock<HashSet<H160>>>;
+
+// The campaign which sign-ups are currently recorded against
+pub const VIP_CAMPAIGN: &str = "vip";
+
+// The latest protocol version, whose message shapes match `Message`
+pub const PROTOCOL_VERSION: u32 = 2;
+// The oldest protocol version still supported, assumed for clients which do not request a version
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+// The request types supported by the server
+pub const REQUESTS: [&str; 6] = [
+    "sign-up",
+    "check",
+    "challenge",
+    "subscribe",
+    "unsubscribe",
+    "resume",
+];
+
+// Interval at which the replica records its number of connected clients
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+// Period after which a replica without a heartbeat is no longer included in peer totals
+pub const HEARTBEAT_EXPIRY: Duration = Duration::from_secs(30);
+// Minimum interval between broadcasts of the sign-up buckets, which are queried afresh for each
+const BUCKETS_INTERVAL: Duration = Duration::from_secs(1);
+// Maximum delay between attempts to re-establish the listener connection
+const MAX_LISTEN_BACKOFF: Duration = Duration::from_secs(30);
+// Number of recent broadcasts retained for replay to reconnecting clients
+const REPLAY_BUFFER: usize = 1_000;
+// Period allowed for a client to authenticate after connecting
+const AUTH_TIMEOUT: Duration = Duration::from_secs(10);
+// Period allowed for the final messages and close frame to be sent to a client on shutdown
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+// Maximum number of wallets tracked per connection, for notices targeted at a wallet
+const MAX_WALLETS: usize = 16;
+// Maximum number of topics a connection may subscribe to
+const MAX_SUBSCRIPTIONS: usize = 16;
+// Websocket close code indicating that the server is going away
+const GOING_AWAY: u16 = 1001;
+// Websocket close code indicating that the client violated policy
+const POLICY_VIOLATION: u16 = 1008;
+// Websocket close code indicating that the server could not fulfil the connection
+const INTERNAL_ERROR: u16 = 1011;
 
 pub struct Hub {
-    tx: broadcast::Sender<String>,
+    tx: broadcast::Sender<Arc<Broadcast>>,
     clients: Clients,
-    pool: db::ConnectionPool,
+    store: Arc<dyn SignUpStore>,
+    // Database used to fan out broadcasts and aggregate presence across replicas, if any
+    pool: Option<db::ConnectionPool>,
     api_key: String,
+    default_topics: HashSet<Topic>,
+    rate_limiter: RateLimiter,
+    // Challenges which sign-ups must solve, if enabled
+    proof_of_work: Option<ProofOfWork>,
+    replica: String,
+    listening: AtomicBool,
+    events: Mutex<VecDeque<Arc<Broadcast>>>,
+    latest_seq: AtomicU64,
+    // Set when addresses have signed up since the sign-up buckets were last broadcast
+    buckets_stale: AtomicBool,
+    // Set to the delay after which clients should reconnect once shutting down
+    shutdown: watch::Sender<Option<Duration>>,
+    connections: AtomicUsize,
 }
 
 impl Hub {
-    pub fn init(pool: db::ConnectionPool, api_key: String) -> Hub {
+    pub fn init(
+        store: Arc<dyn SignUpStore>,
+        pool: Option<db::ConnectionPool>,
+        api_key: String,
+        default_topics: HashSet<Topic>,
+        rate_limiter: RateLimiter,
+        proof_of_work: Option<ProofOfWork>,
+    ) -> Hub {
         let (tx, _rx) = broadcast::channel(10_000);
+        let (shutdown, _rx) = watch::channel(None);
         Hub {
             tx,
             clients: Clients::default(),
+            store,
             pool,
             api_key,
+            default_topics,
+            rate_limiter,
+            proof_of_work,
+            replica: replica_id(),
+            listening: AtomicBool::new(false),
+            events: Mutex::new(VecDeque::with_capacity(REPLAY_BUFFER)),
+            latest_seq: AtomicU64::new(0),
+            buckets_stale: AtomicBool::new(false),
+            shutdown,
+            connections: AtomicUsize::new(0),
         }
     }
 
-    pub fn broadcast(&self, message: Message) -> crate::Result<()> {
-        if let Ok(v) = serde_json::to_string(&message) {
-            if let Err(e) = self.tx.send(v) {
-                tracing::error!("unable to broadcast message {} {:?}", e, message)
+    // Starts listening for messages published by any replica and recording presence, when running with a database
+    pub fn start(self: &Arc<Self>) {
+        let pool = match &self.pool {
+            Some(pool) => pool.clone(),
+            None => {
+                tracing::info!("no database configured, broadcasting to local clients only");
+                return;
+            }
+        };
+        let hub = self.clone();
+        let listen_pool = pool.clone();
+        tokio::spawn(async move { hub.listen(&listen_pool).await });
+        let hub = self.clone();
+        tokio::spawn(async move { hub.heartbeat(&pool).await });
+        let hub = self.clone();
+        tokio::spawn(async move { hub.refresh_buckets().await });
+    }
+
+    // Returns the delay after which clients should reconnect, if the hub is shutting down
+    pub fn shutting_down(&self) -> Option<Duration> {
+        *self.shutdown.borrow()
+    }
+
+    // Asks clients to reconnect after the delay and closes their connections, waiting for in-flight requests to
+    // complete up to the timeout
+    pub async fn shutdown(&self, reconnect_in: Duration, timeout: Duration) {
+        tracing::info!(
+            "shutting down hub with {} connections",
+            self.connections.load(Ordering::Relaxed)
+        );
+        self.shutdown.send_replace(Some(reconnect_in));
+
+        // Wait for connections to drain
+        let deadline = Instant::now() + timeout;
+        while self.connections.load(Ordering::Relaxed) > 0 {
+            if Instant::now() >= deadline {
+                tracing::warn!(
+                    "shutdown timed out with {} connections remaining",
+                    self.connections.load(Ordering::Relaxed)
+                );
+                break;
             }
-            tracing::debug!("{:?}", message);
-        } else {
-            tracing::warn!("unable to serialise message for broadcast {:?}", message)
+            tokio::time::sleep(Duration::from_millis(100)).await;
         }
 
-        Ok(())
+        // Finally remove presence so replica no longer included in peer totals
+        let pool = match &self.pool {
+            Some(pool) => pool,
+            None => return,
+        };
+        let removed = match pool.get_connection().await {
+            Ok(connection) => db::presence::remove(&connection, &self.replica).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = removed {
+            tracing::warn!(
+                "unable to remove presence for replica {}: {}",
+                self.replica,
+                e
+            );
+        }
     }
 
-    async fn auth(&self, receiver: &mut SplitStream<WebSocket>) -> crate::Result<()> {
-        if let Some(Ok(message)) = receiver.next().await {
-            if let axum::extract::ws::Message::Text(value) = message {
-                if self.api_key.eq(value.trim()) {
+    // Publishes the message to clients connected to all replicas
+    pub async fn broadcast(&self, message: Message) -> crate::Result<()> {
+        let payload = match serde_json::to_string(&message) {
+            Ok(payload) => payload,
+            Err(e) => {
+                tracing::warn!(
+                    "unable to serialise message for broadcast {} {:?}",
+                    e,
+                    message
+                );
+                return Ok(());
+            }
+        };
+        tracing::debug!("{:?}", message);
+
+        // Publish via database (which stamps the sequence number), falling back to local clients only if unavailable
+        if let Some(pool) = self
+            .pool
+            .as_ref()
+            .filter(|_| self.listening.load(Ordering::Relaxed))
+        {
+            match publish(pool, &payload).await {
+                Ok(seq) => {
+                    tracing::trace!("published message {}", seq);
                     return Ok(());
                 }
+                Err(e) => tracing::error!("unable to publish message {} {:?}", e, message),
             }
         }
+        self.send_local(Broadcast::new(message, None, payload));
+        Ok(())
+    }
 
-        Err(error::Error::UnauthorisedError)
+    // Sends the broadcast to clients connected to this replica, retaining stamped broadcasts for replay
+    fn send_local(&self, broadcast: Broadcast) {
+        let broadcast = Arc::new(broadcast);
+        if let Some(seq) = broadcast.seq {
+            let mut events = self.events.lock().expect("events poisoned");
+            self.latest_seq.fetch_max(seq, Ordering::Relaxed);
+            retain(&mut events, broadcast.clone());
+        }
+
+        // An error only indicates that there are currently no subscribers
+        if self.tx.send(broadcast).is_err() {
+            tracing::trace!("no local clients to receive broadcast");
+        }
+    }
+
+    // Forwards messages published by any replica on to local clients, reconnecting if the connection is lost
+    async fn listen(&self, pool: &db::ConnectionPool) {
+        let mut backoff = Duration::from_secs(1);
+        loop {
+            match pool.listen(db::hub::CHANNEL).await {
+                Ok(mut listener) => {
+                    tracing::debug!("listening for hub messages on channel {}", db::hub::CHANNEL);
+                    self.listening.store(true, Ordering::Relaxed);
+                    backoff = Duration::from_secs(1);
+                    while let Some(notification) = listener.recv().await {
+                        let payload = notification.payload();
+                        match serde_json::from_str::<Event>(payload) {
+                            Ok(event) => self.send_local(Broadcast::new(
+                                event.message,
+                                Some(event.seq),
+                                payload.to_string(),
+                            )),
+                            Err(e) => tracing::warn!("unsupported hub message {} {}", e, payload),
+                        }
+                    }
+                    self.listening.store(false, Ordering::Relaxed);
+                    tracing::warn!("hub listener connection lost");
+                }
+                Err(e) => tracing::error!("unable to listen for hub messages: {}", e),
+            }
+
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_LISTEN_BACKOFF);
+        }
+    }
+
+    // Periodically records the number of local clients so that peer totals can be aggregated across replicas
+    async fn heartbeat(&self, pool: &db::ConnectionPool) {
+        let mut interval = tokio::time::interval(HEARTBEAT_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = self.record_presence(pool).await {
+                tracing::error!(
+                    "unable to record presence for replica {}: {}",
+                    self.replica,
+                    e
+                );
+                continue;
+            }
+            if let Err(e) = self.prune_presence(pool).await {
+                tracing::warn!("unable to prune expired replicas: {}", e);
+            }
+        }
     }
 
-    pub async fn connect(&self, stream: WebSocket) {
+    // Broadcasts the sign-up buckets whenever addresses have signed up, at most once per interval however many do
+    async fn refresh_buckets(&self) {
+        let mut interval = tokio::time::interval(BUCKETS_INTERVAL);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            if !self.buckets_stale.swap(false, Ordering::Relaxed) {
+                continue;
+            }
+            if let Err(e) = self.buckets().await {
+                tracing::warn!("unable to broadcast current sign-up buckets: {}", e);
+            }
+        }
+    }
+
+    async fn record_presence(&self, pool: &db::ConnectionPool) -> crate::Result<()> {
+        let clients = self.clients.read().await.len() as u64;
+        let connection = pool.get_connection().await?;
+        db::presence::heartbeat(&connection, &self.replica, clients).await
+    }
+
+    async fn prune_presence(&self, pool: &db::ConnectionPool) -> crate::Result<()> {
+        let connection = pool.get_connection().await?;
+        let pruned = db::presence::prune(&connection, HEARTBEAT_EXPIRY).await?;
+        if pruned > 0 {
+            tracing::debug!("pruned {} expired replicas", pruned);
+        }
+        Ok(())
+    }
+
+    // Records local presence and then returns the number of clients connected across all replicas
+    async fn peers(&self) -> crate::Result<u64> {
+        let pool = match &self.pool {
+            Some(pool) => pool,
+            None => return Ok(self.clients.read().await.len() as u64),
+        };
+        self.record_presence(pool).await?;
+        let connection = pool.get_connection().await?;
+        db::presence::total(&connection, HEARTBEAT_EXPIRY).await
+    }
+
+    async fn auth(&self, receiver: &mut SplitStream<WebSocket>) -> crate::Result<()> {
+        if let Some(Ok(axum::extract::ws::Message::Text(value))) = receiver.next().await {
+            if bool::from(value.trim().as_bytes().ct_eq(self.api_key.as_bytes())) {
+                return Ok(());
+            }
+        }
+
+        Err(error::Error::Unauthorised)
+    }
+
+    pub async fn connect(
+        &self,
+        stream: WebSocket,
+        encoding: Encoding,
+        version: u32,
+        ip: IpAddr,
+        mut metadata: Option<SignUpMetadata>,
+    ) {
+        // Subscribe before authenticating, so that a shutdown during authentication is still noticed
+        let mut shutdown = self.shutdown.subscribe();
+
         // Split stream into send/receive channels
         let (mut sender, mut receiver) = stream.split();
 
-        // Authenticate
-        if let Err(e) = self.auth(&mut receiver).await {
+        // Authenticate, allowing a limited period for the client to do so
+        let authenticated = tokio::time::timeout(AUTH_TIMEOUT, self.auth(&mut receiver))
+            .await
+            .unwrap_or(Err(Error::Unauthorised));
+        if let Err(e) = authenticated {
             tracing::error!("client could not be authenticated: {:?}", e);
             let _ = sender.close().await;
             return;
         }
 
+        // Track connection once authenticated until disconnected, so that shutdown can wait for it
+        let _connection = Connected::new(&self.connections);
+
         // Create mpsc channel for sending message from multiple producers
         let (tx, mut rx) = mpsc::channel(10);
         let send_task = tokio::spawn(async move {
             while let Some(msg) = rx.recv().await {
-                // Attempt to forward message on to websocket as text, breaking if error
-                if sender
-                    .send(axum::extract::ws::Message::Text(msg))
-                    .await
-                    .is_err()
-                {
+                // Attempt to forward message on to websocket, breaking if error or connection closed
+                let close = matches!(msg, ws::Message::Close(_));
+                if sender.send(msg).await.is_err() || close {
                     break;
                 }
             }
         });
 
+        // Greet peer with the capabilities of the server, when it requested a version which includes the greeting
+        let sender = MessageSender {
+            tx,
+            encoding,
+            version,
+        };
+        sender.send(self.hello(version)).await;
+
+        // Update peer with number of sign-ups on join, closing the connection should they be unavailable
+        let sign_ups = match self.store.total().await {
+            Ok(sign_ups) => sign_ups,
+            Err(e) => {
+                tracing::error!("unable to determine sign-ups for client: {}", e);
+                sender
+                    .close(
+                        send_task,
+                        Message::error(&e),
+                        INTERNAL_ERROR,
+                        "internal error",
+                    )
+                    .await;
+                return;
+            }
+        };
+
         // Create client identifier and track number of peers
         let id = NEXT_USERID.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
         tracing::debug!("client {} connected", id);
         self.clients.write().await.insert(id);
+        if let Some(metadata) = &mut metadata {
+            metadata.connection_id = Some(format!("{}-{}", self.replica, id));
+        }
 
-        // Update peer with number of sign-ups on join
-        let connection = self.pool.get_connection().await.unwrap();
-        let sign_ups = db::vip::total(&connection).await.unwrap();
-        let sender = MessageSender(tx.clone());
         sender
             .send(Message::SignedUp {
                 total: sign_ups.total,
-                address: None,
+                signed_up: None,
                 last_signed_up: sign_ups.last_signed_up,
                 status: sign_ups.status,
             })
             .await;
 
-        // Subscribe client to broadcasts (broadcast messages received are sent on to client)
+        // Subscribe client to broadcasts (broadcast messages received on subscribed topics are sent on to client)
+        let subscriptions = Subscriptions::new(RwLock::new(self.default_topics.clone()));
+        // Wallets the client has signed up, which are sent any notices targeted at them
+        let wallets = Wallets::default();
         let mut broadcast = self.tx.subscribe();
-        let sender = tx.clone();
+        let forwarder = sender.clone();
+        let topics = subscriptions.clone();
+        let recipients = wallets.clone();
         let broadcast_task = tokio::spawn(async move {
             while let Ok(msg) = broadcast.recv().await {
+                let deliver = match msg.message.recipient() {
+                    Some(address) => recipients
+                        .read()
+                        .expect("wallets poisoned")
+                        .contains(&address),
+                    None => msg.subscribed(&topics),
+                };
+                if !deliver {
+                    continue;
+                }
                 // Anything sent to broadcast channel should be forwarded to sender, breaking if error
-                if sender.send(msg).await.is_err() {
+                if !forwarder.forward(&msg).await {
                     break;
                 }
             }
         });
 
         // Broadcast peer joined
-        if let Err(e) = self.broadcast(Message::PeerJoined {
-            total: self.clients.read().await.len() as u64,
-            last_joined: Some(chrono::Utc::now()),
-        }) {
-            tracing::warn!("unable to notify clients of peer {} joining: {}", id, e);
-        }
-
-        // Wait for next text message from peer
-        while let Some(Ok(message)) = receiver.next().await {
-            if let axum::extract::ws::Message::Text(value) = message {
-                // Attempt to parse/process message
-                if let Ok(m) = serde_json::from_str::<Request>(value.as_str()) {
-                    if let Err(e) = self.process(m, MessageSender(tx.clone())).await {
-                        tracing::error!("unable to process the message {} {:?}", e, value)
+        match self.peers().await {
+            Ok(total) => {
+                if let Err(e) = self
+                    .broadcast(Message::PeerJoined {
+                        total,
+                        last_joined: Some(chrono::Utc::now()),
+                    })
+                    .await
+                {
+                    tracing::warn!("unable to notify clients of peer {} joining: {}", id, e);
+                }
+            }
+            Err(e) => tracing::warn!("unable to determine peers after {} joined: {}", id, e),
+        }
+
+        // Wait for next data message from peer, until disconnected or shutting down (allowing any request in-flight to
+        // be processed)
+        let mut buckets = ConnectionBuckets::default();
+        let mut close = None;
+        loop {
+            let message = tokio::select! {
+                message = receiver.next() => message,
+                _ = shutdown.changed() => {
+                    if let Some(reconnect_in) = *shutdown.borrow() {
+                        close = Some((Message::Restarting { reconnect_in: reconnect_in.as_secs() }, GOING_AWAY, "server restarting"));
                     }
+                    break;
                 }
-            } else {
-                tracing::debug!("unsupported message: {:?}", message);
+            };
+            let message = match message {
+                Some(Ok(message)) => message,
+                _ => break,
+            };
+
+            // Attempt to decode/process message using the negotiated encoding, subject to rate limits
+            match encoding.decode::<Request>(&message) {
+                Some(Ok(m)) => {
+                    if let Err(e) = self
+                        .rate_limiter
+                        .check(m.kind(), &mut buckets, ip, m.address())
+                    {
+                        tracing::debug!("client {} throttled: {}", id, e);
+                        let error = Message::error(&e);
+                        if self.rate_limiter.violation(&mut buckets) {
+                            tracing::warn!("client {} disconnected for exceeding rate limits", id);
+                            close = Some((error, POLICY_VIOLATION, "rate limits exceeded"));
+                            break;
+                        }
+                        sender.send(error).await;
+                        continue;
+                    }
+                    match self
+                        .process(
+                            m,
+                            sender.clone(),
+                            &subscriptions,
+                            &wallets,
+                            metadata.as_ref(),
+                        )
+                        .await
+                    {
+                        Ok(()) => {}
+                        // Report refusals to the client
+                        Err(
+                            e @ (Error::AddressBlocked(_)
+                            | Error::ChallengeRequired
+                            | Error::InvalidChallenge(_)
+                            | Error::InvalidTopic(_)),
+                        ) => {
+                            tracing::info!("client {} refused: {}", id, e);
+                            sender.send(Message::error(&e)).await;
+                        }
+                        Err(e) => {
+                            tracing::error!("unable to process the message {} {:?}", e, message)
+                        }
+                    }
+                }
+                Some(Err(e)) => tracing::debug!("unable to decode message {} {:?}", e, message),
+                None => tracing::debug!("unsupported message: {:?}", message),
             }
         }
 
-        // Finally unsubscribe client
-        send_task.abort();
+        // Finally unsubscribe client, sending a final message and closing the connection if required
         broadcast_task.abort();
+        match close {
+            Some((message, code, reason)) => sender.close(send_task, message, code, reason).await,
+            None => send_task.abort(),
+        }
         self.clients.write().await.remove(&id);
         tracing::debug!("client {} disconnected", id);
 
         // Broadcast peer left to remaining subscribers
-        if let Err(e) = self.broadcast(Message::PeerLeft {
-            total: self.clients.read().await.len() as u64,
-            last_left: Some(chrono::Utc::now()),
-        }) {
-            tracing::warn!("unable to notify clients of peer {} leaving: {}", id, e);
+        match self.peers().await {
+            Ok(total) => {
+                if let Err(e) = self
+                    .broadcast(Message::PeerLeft {
+                        total,
+                        last_left: Some(chrono::Utc::now()),
+                    })
+                    .await
+                {
+                    tracing::warn!("unable to notify clients of peer {} leaving: {}", id, e);
+                }
+            }
+            Err(e) => tracing::warn!("unable to determine peers after {} left: {}", id, e),
+        }
+    }
+
+    fn hello(&self, version: u32) -> Message {
+        Message::Hello {
+            protocol_version: version,
+            supported_versions: (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).collect(),
+            requests: REQUESTS
+                .iter()
+                .filter(|r| **r != "challenge" || self.proof_of_work.is_some())
+                .map(|r| r.to_string())
+                .collect(),
+            server_time: Utc::now(),
+            features: ["subscriptions", "msgpack", "cbor", "resume", "rate-limits"]
+                .iter()
+                .chain(self.proof_of_work.as_ref().map(|_| &"proof-of-work"))
+                .chain(self.pool.as_ref().map(|_| &"stats"))
+                .map(|f| f.to_string())
+                .collect(),
         }
     }
 
@@ -153,69 +574,450 @@ impl Hub {
         &self,
         message: Request,
         sender: MessageSender,
+        subscriptions: &Subscriptions,
+        wallets: &Wallets,
+        metadata: Option<&SignUpMetadata>,
     ) -> Result<(), crate::error::Error> {
-        let connection = self.pool.get_connection().await?;
-
-        let mut signed_up: bool;
         match message {
-            Request::SignUp { address } => {
+            Request::SignUp { address, solution } => {
                 tracing::debug!("sign-up received");
 
-                // Check if address already signed up
-                signed_up = db::vip::check(&connection, address).await?;
-                if !signed_up {
-                    // Sign up address
-                    match db::vip::sign_up(&connection, address).await {
-                        Ok(_) => signed_up = true,
-                        Err(e) => match e {
-                            Error::VIPSignupClosed => {}
-                            _ => {
-                                return Err(e);
-                            }
-                        },
+                // Only accept sign-ups with a valid solution to a challenge, when required. Only verified sign-ups
+                // count towards the sign-up rate, so that malformed or unsolved requests cannot raise the difficulty.
+                let redemption = match &self.proof_of_work {
+                    Some(proof_of_work) => {
+                        let redemption = proof_of_work.verify(solution.as_ref(), address).await?;
+                        proof_of_work.record_sign_up();
+                        Some(redemption)
+                    }
+                    None => None,
+                };
+
+                let signed_up = self.sign_up(address, metadata, wallets).await;
+                // The challenge is released should the sign-up fail, so that it can be used again
+                if let (Some(proof_of_work), Some(redemption)) = (&self.proof_of_work, redemption) {
+                    if !matches!(signed_up, Ok(true)) {
+                        if let Err(e) = proof_of_work.release(redemption).await {
+                            tracing::warn!("unable to release challenge: {}", e);
+                        }
                     }
                 }
+                let signed_up = signed_up?;
+                self.signed_up(signed_up, &sender).await
             }
             Request::Check { address } => {
                 tracing::debug!("check received");
 
-                signed_up = db::vip::check(&connection, address).await?;
+                let signed_up = self.store.check(address).await?;
+                self.signed_up(signed_up, &sender).await
+            }
+            Request::Challenge => {
+                let challenge = self
+                    .proof_of_work
+                    .as_ref()
+                    .ok_or(Error::InvalidChallenge("not required"))?
+                    .challenge();
+                sender
+                    .send(Message::Challenge {
+                        challenge: challenge.challenge,
+                        difficulty: challenge.difficulty,
+                        expires_at: challenge.expires_at,
+                    })
+                    .await;
+                Ok(())
+            }
+            Request::Subscribe { topics } => {
+                let topics = {
+                    let mut subscriptions = subscriptions.write().expect("subscriptions poisoned");
+                    let added: HashSet<Topic> = topics
+                        .into_iter()
+                        .filter(|topic| !subscriptions.contains(topic))
+                        .collect();
+                    if subscriptions.len() + added.len() > MAX_SUBSCRIPTIONS {
+                        return Err(Error::InvalidTopic(format!(
+                            "at most {} topics may be subscribed to",
+                            MAX_SUBSCRIPTIONS
+                        )));
+                    }
+                    subscriptions.extend(added);
+                    subscriptions.iter().cloned().collect()
+                };
+                sender.send(Message::Subscriptions { topics }).await;
+                Ok(())
+            }
+            Request::Unsubscribe { topics } => {
+                let topics = {
+                    let mut subscriptions = subscriptions.write().expect("subscriptions poisoned");
+                    for topic in &topics {
+                        subscriptions.remove(topic);
+                    }
+                    subscriptions.iter().cloned().collect()
+                };
+                sender.send(Message::Subscriptions { topics }).await;
+                Ok(())
+            }
+            Request::Resume { after_seq } => {
+                tracing::debug!("resume received after {}", after_seq);
+
+                self.resume(after_seq, &sender, subscriptions).await
             }
         }
+    }
 
-        // Broadcast updated total to clients
-        let signups = db::vip::total(&connection).await?;
+    // Signs up the address if not already signed up, returning whether the address is signed up. Only addresses newly
+    // signed up are added to the client's wallets, so that notices are not sent to clients merely knowing an address.
+    async fn sign_up(
+        &self,
+        address: H160,
+        metadata: Option<&SignUpMetadata>,
+        wallets: &Wallets,
+    ) -> crate::Result<bool> {
+        match self.store.sign_up(address, metadata).await {
+            Ok(SignUpOutcome::Created(sign_up)) => {
+                tracing::debug!(
+                    "{:x} signed up at {}",
+                    sign_up.address,
+                    sign_up.signed_up_at
+                );
+                {
+                    let mut wallets = wallets.write().expect("wallets poisoned");
+                    if wallets.len() < MAX_WALLETS {
+                        wallets.insert(address);
+                    }
+                }
+                self.buckets_stale.store(true, Ordering::Relaxed);
+                Ok(true)
+            }
+            Ok(SignUpOutcome::Existing(sign_up)) => {
+                tracing::debug!(
+                    "{:x} already signed up at {}",
+                    sign_up.address,
+                    sign_up.signed_up_at
+                );
+                Ok(true)
+            }
+            Err(Error::VIPSignupClosed) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Broadcasts the current total and status to clients, returning them
+    pub async fn announce(&self) -> crate::Result<SignUps> {
+        let signups = self.store.total().await?;
         self.broadcast(Message::SignedUp {
             total: signups.total,
-            address: None,
+            signed_up: None,
             last_signed_up: signups.last_signed_up,
             status: signups.status,
-        })?;
+        })
+        .await?;
+        Ok(signups)
+    }
+
+    // Broadcasts the sign-up counts of the buckets currently in progress, when running with a database
+    async fn buckets(&self) -> crate::Result<()> {
+        let pool = match &self.pool {
+            Some(pool) => pool,
+            None => return Ok(()),
+        };
+        let current = db::stats::current(&*pool.get_connection().await?).await?;
+        self.broadcast(Message::Buckets {
+            minute: current.minute,
+            hour: current.hour,
+            day: current.day,
+        })
+        .await
+    }
+
+    // Broadcasts the updated total following the removal of sign-ups, notifying any clients with the removed wallets
+    pub async fn removed(&self, addresses: &[H160], reason: Option<&str>) -> crate::Result<()> {
+        self.announce().await?;
+        for address in addresses {
+            self.broadcast(Message::Removed {
+                address: *address,
+                reason: reason.map(str::to_string),
+            })
+            .await?;
+        }
+        Ok(())
+    }
+
+    // Broadcasts the updated total to clients, replying to the sender with their sign-up status
+    async fn signed_up(&self, signed_up: bool, sender: &MessageSender) -> crate::Result<()> {
+        let signups = self.announce().await?;
 
         // Send checked message back to sender with signup status
         sender
             .send(Message::SignedUp {
                 total: signups.total,
-                address: Some(signed_up),
+                signed_up: Some(signed_up),
                 last_signed_up: signups.last_signed_up,
                 status: signups.status,
             })
             .await;
         Ok(())
     }
+
+    // Replays broadcasts missed since the sequence number, or sends a snapshot when they are no longer buffered
+    async fn resume(
+        &self,
+        after_seq: u64,
+        sender: &MessageSender,
+        subscriptions: &Subscriptions,
+    ) -> crate::Result<()> {
+        let (missed, latest_seq) = {
+            // The latest sequence number is only updated whilst holding the lock, so is consistent with the buffer
+            let events = self.events.lock().expect("events poisoned");
+            let latest_seq = self.latest_seq.load(Ordering::Relaxed);
+            (missed(&events, after_seq, latest_seq), latest_seq)
+        };
+
+        match missed {
+            Some(missed) => {
+                for event in missed
+                    .iter()
+                    .filter(|event| event.subscribed(subscriptions))
+                {
+                    sender.forward(event).await;
+                }
+            }
+            None => {
+                // A replica which has only just started takes the sequence number from the database instead
+                let seq = match &self.pool {
+                    Some(pool) if latest_seq == 0 => {
+                        db::hub::latest(&pool.get_connection().await?).await?
+                    }
+                    _ => latest_seq,
+                };
+                let signups = self.store.total().await?;
+                let peers = self.peers().await?;
+                sender
+                    .send(Message::Snapshot {
+                        seq,
+                        total: signups.total,
+                        last_signed_up: signups.last_signed_up,
+                        status: signups.status,
+                        peers,
+                    })
+                    .await;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Request {
+    // The type of the request, as used for rate limiting
+    fn kind(&self) -> &'static str {
+        match self {
+            Request::SignUp { .. } => "sign-up",
+            Request::Check { .. } => "check",
+            Request::Challenge => "challenge",
+            Request::Subscribe { .. } => "subscribe",
+            Request::Unsubscribe { .. } => "unsubscribe",
+            Request::Resume { .. } => "resume",
+        }
+    }
+
+    // The wallet address the request relates to, if any
+    fn address(&self) -> Option<H160> {
+        match self {
+            Request::SignUp { address, .. } | Request::Check { address } => Some(*address),
+            _ => None,
+        }
+    }
 }
 
-struct MessageSender(mpsc::Sender<String>);
+async fn publish(pool: &db::ConnectionPool, payload: &str) -> crate::Result<u64> {
+    let connection = pool.get_connection().await?;
+    db::hub::publish(&connection, payload).await
+}
+
+// Retains the broadcast for replay, discarding the oldest once the buffer is full
+fn retain(events: &mut VecDeque<Arc<Broadcast>>, broadcast: Arc<Broadcast>) {
+    if events.len() == REPLAY_BUFFER {
+        events.pop_front();
+    }
+    events.push_back(broadcast);
+}
+
+// The events missed since the sequence number, or none when they cannot all be replayed from the buffer (in which case
+// a snapshot is required)
+fn missed(
+    events: &VecDeque<Arc<Broadcast>>,
+    after_seq: u64,
+    latest_seq: u64,
+) -> Option<Vec<Arc<Broadcast>>> {
+    match events.front() {
+        // Only replay if the buffer covers every event after the requested sequence number
+        Some(oldest)
+            if after_seq <= latest_seq && oldest.seq <= Some(after_seq.saturating_add(1)) =>
+        {
+            Some(
+                events
+                    .iter()
+                    .filter(|event| event.seq > Some(after_seq))
+                    .cloned()
+                    .collect(),
+            )
+        }
+        // Nothing missed, provided this replica has seen the requested event (a replica which has only just started
+        // has not, so cannot tell what was missed)
+        _ if latest_seq > 0 && after_seq == latest_seq => Some(Vec::new()),
+        _ => None,
+    }
+}
+
+// Tracks a connection for as long as it is held
+struct Connected<'a>(&'a AtomicUsize);
+
+impl<'a> Connected<'a> {
+    fn new(connections: &'a AtomicUsize) -> Self {
+        connections.fetch_add(1, Ordering::Relaxed);
+        Connected(connections)
+    }
+}
+
+impl Drop for Connected<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// Selects the protocol version for a client, defaulting to the oldest version for clients which do not request one
+pub fn negotiate_version(requested: Option<u32>) -> Option<u32> {
+    match requested {
+        None => Some(MIN_PROTOCOL_VERSION),
+        Some(version) if version < MIN_PROTOCOL_VERSION => None,
+        Some(version) => Some(version.min(PROTOCOL_VERSION)),
+    }
+}
+
+// Identifies the replica, so that presence can be aggregated across replicas
+fn replica_id() -> String {
+    let host = std::env::var("HOSTNAME").unwrap_or_else(|_| "localhost".to_string());
+    format!("{}-{}", host, std::process::id())
+}
+
+// A message published by any replica, stamped with its sequence number
+#[derive(Deserialize)]
+struct Event {
+    seq: u64,
+    #[serde(flatten)]
+    message: Message,
+}
+
+// A message published to clients, along with the topics it relates to
+#[derive(Debug)]
+struct Broadcast {
+    topics: Vec<Topic>,
+    // Sequence number, unless published locally whilst unable to publish via the database
+    seq: Option<u64>,
+    message: Message,
+    // Payloads are only encoded when required, at most once per encoding and protocol version
+    payloads: [[OnceLock<Option<ws::Message>>; PROTOCOL_VERSION as usize]; Encoding::ALL.len()],
+}
+
+impl Broadcast {
+    // Creates a broadcast from the message and its JSON payload for the latest protocol version, which has already
+    // been serialised for publishing
+    fn new(message: Message, seq: Option<u64>, json: String) -> Broadcast {
+        let broadcast = Broadcast {
+            topics: message.topics(),
+            seq,
+            message,
+            payloads: Default::default(),
+        };
+        let _ = broadcast.payloads[Encoding::Json.index()][PROTOCOL_VERSION as usize - 1]
+            .set(Some(ws::Message::Text(json)));
+        broadcast
+    }
+
+    fn payload(&self, encoding: Encoding, version: u32) -> Option<ws::Message> {
+        self.payloads[encoding.index()][version as usize - 1]
+            .get_or_init(|| {
+                if version < self.message.since() {
+                    return None;
+                }
+                let message = Versioned(&self.message, version);
+                // Sequence numbers were introduced in version 2
+                let encoded = match self.seq {
+                    Some(seq) if version >= 2 => encoding.encode(&Stamped { seq, message }),
+                    _ => encoding.encode(&message),
+                };
+                match encoded {
+                    Ok(payload) => Some(payload),
+                    Err(e) => {
+                        tracing::warn!(
+                            "unable to encode message for broadcast {} {:?}",
+                            e,
+                            self.message
+                        );
+                        None
+                    }
+                }
+            })
+            .clone()
+    }
+
+    fn subscribed(&self, subscriptions: &Subscriptions) -> bool {
+        let subscriptions = subscriptions.read().expect("subscriptions poisoned");
+        self.topics
+            .iter()
+            .any(|topic| subscriptions.contains(topic))
+    }
+}
+
+#[derive(Clone)]
+struct MessageSender {
+    tx: mpsc::Sender<ws::Message>,
+    encoding: Encoding,
+    version: u32,
+}
 
 impl MessageSender {
+    // Forwards the broadcast in the client's encoding and protocol version, returning whether the client is still connected
+    async fn forward(&self, broadcast: &Broadcast) -> bool {
+        match broadcast.payload(self.encoding, self.version) {
+            Some(payload) => self.tx.send(payload).await.is_ok(),
+            None => true,
+        }
+    }
+
     async fn send(&self, message: Message) {
-        if let Ok(v) = serde_json::to_string(&message) {
-            if let Err(e) = self.0.send(v).await {
-                tracing::error!("unable to send message {} {:?}", e, message)
+        if self.version < message.since() {
+            return;
+        }
+        match self.encoding.encode(&Versioned(&message, self.version)) {
+            Ok(v) => {
+                if let Err(e) = self.tx.send(v).await {
+                    tracing::error!("unable to send message {} {:?}", e, message)
+                }
+                tracing::debug!("{:?}", message);
             }
-            tracing::debug!("{:?}", message);
-        } else {
-            tracing::warn!("unable to serialise message for sending {:?}", message)
+            Err(e) => tracing::warn!("unable to encode message for sending {} {:?}", e, message),
+        }
+    }
+
+    // Sends a final message followed by a close frame, allowing a short period for them to be sent
+    async fn close(
+        &self,
+        mut send_task: JoinHandle<()>,
+        message: Message,
+        code: u16,
+        reason: &'static str,
+    ) {
+        self.send(message).await;
+        let close = ws::Message::Close(Some(CloseFrame {
+            code,
+            reason: reason.into(),
+        }));
+        let _ = self.tx.send(close).await;
+        if tokio::time::timeout(CLOSE_TIMEOUT, &mut send_task)
+            .await
+            .is_err()
+        {
+            send_task.abort();
         }
     }
 }
@@ -224,18 +1026,39 @@ impl MessageSender {
 #[serde(tag = "type")]
 pub enum Request {
     #[serde(rename = "sign-up")]
-    SignUp { address: H160 },
+    SignUp {
+        address: H160,
+        // Solution to a challenge, required when proof-of-work is enabled
+        #[serde(default)]
+        solution: Option<Solution>,
+    },
     #[serde(rename = "check")]
     Check { address: H160 },
+    #[serde(rename = "challenge")]
+    Challenge,
+    #[serde(rename = "subscribe")]
+    Subscribe { topics: Vec<Topic> },
+    #[serde(rename = "unsubscribe")]
+    Unsubscribe { topics: Vec<Topic> },
+    #[serde(rename = "resume")]
+    Resume { after_seq: u64 },
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(tag = "type")]
 pub enum Message {
+    #[serde(rename = "hello")]
+    Hello {
+        protocol_version: u32,
+        supported_versions: Vec<u32>,
+        requests: Vec<String>,
+        server_time: DateTime<Utc>,
+        features: Vec<String>,
+    },
     #[serde(rename = "signed-up")]
     SignedUp {
         total: u64,
-        address: Option<bool>,
+        signed_up: Option<bool>,
         last_signed_up: Option<DateTime<Utc>>,
         status: Status,
     },
@@ -249,4 +1072,383 @@ pub enum Message {
         total: u64,
         last_left: Option<DateTime<Utc>>,
     },
+    #[serde(rename = "subscriptions")]
+    Subscriptions { topics: Vec<Topic> },
+    #[serde(rename = "restarting")]
+    Restarting { reconnect_in: u64 },
+    #[serde(rename = "challenge")]
+    Challenge {
+        challenge: String,
+        difficulty: u32,
+        expires_at: DateTime<Utc>,
+    },
+    #[serde(rename = "error")]
+    Error {
+        code: ErrorCode,
+        message: String,
+        retry_after_ms: Option<u64>,
+    },
+    // Notifies a client that the sign-up of its wallet was removed
+    #[serde(rename = "removed")]
+    Removed {
+        address: H160,
+        reason: Option<String>,
+    },
+    // A message from the operators, such as news of the mint
+    #[serde(rename = "announcement")]
+    Announcement {
+        message: String,
+        announced_at: DateTime<Utc>,
+    },
+    // Sign-up counts of the minute, hour and day (UTC) in progress, following a sign-up
+    #[serde(rename = "buckets")]
+    Buckets {
+        minute: CurrentBucket,
+        hour: CurrentBucket,
+        day: CurrentBucket,
+    },
+    #[serde(rename = "snapshot")]
+    Snapshot {
+        seq: u64,
+        total: u64,
+        last_signed_up: Option<DateTime<Utc>>,
+        status: Status,
+        peers: u64,
+    },
+}
+
+impl Message {
+    // The topics a client must be subscribed to (any of) in order to receive the message
+    fn topics(&self) -> Vec<Topic> {
+        match self {
+            Message::SignedUp { .. } => vec![
+                Topic::Totals,
+                Topic::Status,
+                Topic::Campaign(VIP_CAMPAIGN.to_string()),
+            ],
+            Message::PeerJoined { .. } | Message::PeerLeft { .. } => vec![Topic::Presence],
+            Message::Buckets { .. } => vec![Topic::Stats],
+            Message::Announcement { .. } => vec![Topic::Announcements],
+            Message::Hello { .. }
+            | Message::Subscriptions { .. }
+            | Message::Restarting { .. }
+            | Message::Challenge { .. }
+            | Message::Error { .. }
+            | Message::Removed { .. }
+            | Message::Snapshot { .. } => Vec::new(),
+        }
+    }
+
+    // The protocol version which introduced the message, which is not sent to clients using an earlier version
+    fn since(&self) -> u32 {
+        match self {
+            Message::SignedUp { .. } | Message::PeerJoined { .. } | Message::PeerLeft { .. } => 1,
+            Message::Hello { .. }
+            | Message::Subscriptions { .. }
+            | Message::Restarting { .. }
+            | Message::Challenge { .. }
+            | Message::Error { .. }
+            | Message::Removed { .. }
+            | Message::Announcement { .. }
+            | Message::Buckets { .. }
+            | Message::Snapshot { .. } => 2,
+        }
+    }
+
+    // The wallet a message is targeted at, which is then only sent to clients with the wallet regardless of topics
+    fn recipient(&self) -> Option<H160> {
+        match self {
+            Message::Removed { address, .. } => Some(*address),
+            _ => None,
+        }
+    }
+
+    // Reports an error which prevented a request from being processed
+    fn error(error: &Error) -> Message {
+        let (code, retry_after) = match error {
+            Error::RateLimited { retry_after, .. } => (ErrorCode::RateLimited, Some(*retry_after)),
+            Error::AddressBlocked(_) => (ErrorCode::AddressBlocked, None),
+            Error::ChallengeRequired => (ErrorCode::ChallengeRequired, None),
+            Error::InvalidChallenge(_) => (ErrorCode::InvalidChallenge, None),
+            Error::InvalidTopic(_) => (ErrorCode::InvalidTopic, None),
+            _ => (ErrorCode::Internal, None),
+        };
+        Message::Error {
+            code,
+            message: error.to_string(),
+            retry_after_ms: retry_after.map(|retry_after| retry_after.as_millis() as u64),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone)]
+pub enum ErrorCode {
+    #[serde(rename = "internal")]
+    Internal,
+    #[serde(rename = "rate-limited")]
+    RateLimited,
+    #[serde(rename = "address-blocked")]
+    AddressBlocked,
+    #[serde(rename = "challenge-required")]
+    ChallengeRequired,
+    #[serde(rename = "invalid-challenge")]
+    InvalidChallenge,
+    #[serde(rename = "invalid-topic")]
+    InvalidTopic,
+}
+
+// A message serialised using the shapes of a particular protocol version
+struct Versioned<'a>(&'a Message, u32);
+
+impl Serialize for Versioned<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match (self.0, self.1) {
+            // Version 1 reported whether the address was signed up as `address`
+            (
+                Message::SignedUp {
+                    total,
+                    signed_up,
+                    last_signed_up,
+                    status,
+                },
+                1,
+            ) => SignedUpV1 {
+                total: *total,
+                address: *signed_up,
+                last_signed_up: *last_signed_up,
+                status: *status,
+            }
+            .serialize(serializer),
+            (message, _) => message.serialize(serializer),
+        }
+    }
+}
+
+// A message stamped with its sequence number
+#[derive(Serialize)]
+struct Stamped<'a> {
+    seq: u64,
+    #[serde(flatten)]
+    message: Versioned<'a>,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename = "signed-up")]
+struct SignedUpV1 {
+    total: u64,
+    address: Option<bool>,
+    last_signed_up: Option<DateTime<Utc>>,
+    status: Status,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(try_from = "String", into = "String")]
+pub enum Topic {
+    Totals,
+    Presence,
+    Campaign(String),
+    Status,
+    Announcements,
+    Stats,
+}
+
+impl fmt::Display for Topic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Topic::Totals => write!(f, "totals"),
+            Topic::Presence => write!(f, "presence"),
+            Topic::Campaign(campaign) => write!(f, "campaign:{}", campaign),
+            Topic::Status => write!(f, "status"),
+            Topic::Announcements => write!(f, "announcements"),
+            Topic::Stats => write!(f, "stats"),
+        }
+    }
+}
+
+impl FromStr for Topic {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim() {
+            "totals" => Ok(Topic::Totals),
+            "presence" => Ok(Topic::Presence),
+            "status" => Ok(Topic::Status),
+            "announcements" => Ok(Topic::Announcements),
+            "stats" => Ok(Topic::Stats),
+            topic => match topic.strip_prefix("campaign:") {
+                // Only campaigns which are broadcast may be subscribed to
+                Some(campaign) if campaign == VIP_CAMPAIGN => {
+                    Ok(Topic::Campaign(campaign.to_string()))
+                }
+                _ => Err(Error::InvalidTopic(topic.to_string())),
+            },
+        }
+    }
+}
+
+impl TryFrom<String> for Topic {
+    type Error = Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Topic> for String {
+    fn from(topic: Topic) -> Self {
+        topic.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn events(seqs: std::ops::RangeInclusive<u64>) -> VecDeque<Arc<Broadcast>> {
+        let mut events = VecDeque::new();
+        for seq in seqs {
+            let message = Message::PeerJoined {
+                total: seq,
+                last_joined: None,
+            };
+            retain(
+                &mut events,
+                Arc::new(Broadcast::new(message, Some(seq), String::new())),
+            );
+        }
+        events
+    }
+
+    fn seqs(missed: Option<Vec<Arc<Broadcast>>>) -> Option<Vec<u64>> {
+        missed.map(|missed| missed.iter().map(|event| event.seq.unwrap()).collect())
+    }
+
+    #[test]
+    fn gaps_within_the_buffer_are_replayed() {
+        let events = events(5..=10);
+        assert_eq!(seqs(missed(&events, 7, 10)), Some(vec![8, 9, 10]));
+        // The oldest buffered event immediately follows the requested one
+        assert_eq!(seqs(missed(&events, 4, 10)), Some((5..=10).collect()));
+        assert_eq!(seqs(missed(&events, 10, 10)), Some(Vec::new()));
+    }
+
+    #[test]
+    fn gaps_older_than_the_buffer_require_a_snapshot() {
+        let events = events(1..=REPLAY_BUFFER as u64 + 5);
+        assert_eq!(events.len(), REPLAY_BUFFER);
+        assert_eq!(events.front().unwrap().seq, Some(6));
+        assert_eq!(seqs(missed(&events, 4, REPLAY_BUFFER as u64 + 5)), None);
+        assert_eq!(
+            seqs(missed(&events, 5, REPLAY_BUFFER as u64 + 5)).map(|seqs| seqs.len()),
+            Some(REPLAY_BUFFER)
+        );
+    }
+
+    #[test]
+    fn sequence_numbers_ahead_of_this_replica_require_a_snapshot() {
+        let events = events(5..=10);
+        assert_eq!(seqs(missed(&events, 11, 10)), None);
+        // A replica yet to see any event cannot tell what was missed
+        assert_eq!(seqs(missed(&VecDeque::new(), 0, 0)), None);
+        assert_eq!(seqs(missed(&VecDeque::new(), 3, 0)), None);
+        // Whereas one which has seen the event, but no longer buffers any, knows nothing was missed
+        assert_eq!(seqs(missed(&VecDeque::new(), 10, 10)), Some(Vec::new()));
+    }
+
+    fn signed_up() -> Message {
+        Message::SignedUp {
+            total: 42,
+            signed_up: Some(true),
+            last_signed_up: Some(Utc.timestamp(1_650_000_000, 0)),
+            status: Status::Open,
+        }
+    }
+
+    #[test]
+    fn version_1_reports_the_sign_up_as_address() {
+        assert_eq!(
+            serde_json::to_value(Versioned(&signed_up(), 1)).unwrap(),
+            serde_json::json!({
+                "type": "signed-up",
+                "total": 42,
+                "address": true,
+                "last_signed_up": "2022-04-15T05:20:00Z",
+                "status": "Open",
+            })
+        );
+    }
+
+    #[test]
+    fn version_2_reports_the_sign_up_as_signed_up() {
+        assert_eq!(
+            serde_json::to_value(Versioned(&signed_up(), 2)).unwrap(),
+            serde_json::json!({
+                "type": "signed-up",
+                "total": 42,
+                "signed_up": true,
+                "last_signed_up": "2022-04-15T05:20:00Z",
+                "status": "Open",
+            })
+        );
+        // Broadcasts are stamped with their sequence number from version 2
+        assert_eq!(
+            serde_json::to_value(Stamped {
+                seq: 7,
+                message: Versioned(&signed_up(), 2),
+            })
+            .unwrap(),
+            serde_json::json!({
+                "seq": 7,
+                "type": "signed-up",
+                "total": 42,
+                "signed_up": true,
+                "last_signed_up": "2022-04-15T05:20:00Z",
+                "status": "Open",
+            })
+        );
+    }
+
+    #[test]
+    fn hello_is_only_sent_from_version_2() {
+        let hello = Message::Hello {
+            protocol_version: 2,
+            supported_versions: vec![1, 2],
+            requests: REQUESTS.iter().map(|r| r.to_string()).collect(),
+            server_time: Utc.timestamp(1_650_000_000, 0),
+            features: vec!["subscriptions".to_string(), "resume".to_string()],
+        };
+        assert_eq!(
+            serde_json::to_value(Versioned(&hello, 2)).unwrap(),
+            serde_json::json!({
+                "type": "hello",
+                "protocol_version": 2,
+                "supported_versions": [1, 2],
+                "requests": REQUESTS,
+                "server_time": "2022-04-15T05:20:00Z",
+                "features": ["subscriptions", "resume"],
+            })
+        );
+        assert_eq!(hello.since(), 2);
+        assert_eq!(signed_up().since(), 1);
+
+        // Messages introduced by later versions are not broadcast to clients using earlier versions
+        let broadcast = Broadcast::new(hello, Some(1), String::new());
+        assert!(broadcast.payload(Encoding::Json, 1).is_none());
+        let broadcast = Broadcast::new(signed_up(), Some(1), String::new());
+        assert_eq!(
+            broadcast.payload(Encoding::Json, 1),
+            Some(ws::Message::Text(
+                serde_json::to_string(&Versioned(&signed_up(), 1)).unwrap()
+            ))
+        );
+    }
+
+    #[test]
+    fn versions_are_negotiated() {
+        assert_eq!(negotiate_version(None), Some(MIN_PROTOCOL_VERSION));
+        assert_eq!(negotiate_version(Some(0)), None);
+        assert_eq!(negotiate_version(Some(1)), Some(1));
+        assert_eq!(negotiate_version(Some(99)), Some(PROTOCOL_VERSION));
+    }
 }
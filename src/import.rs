@@ -0,0 +1,508 @@
+use crate::db;
+use crate::error::Error;
+use crate::export::checksum;
+use crate::models::parse_address;
+use crate::models::{Actor, Registration};
+use clap::ArgEnum;
+use primitive_types::H160;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::hash_map::{Entry, HashMap};
+
+// Maximum length of a tier, matching the column
+const MAX_TIER_LENGTH: usize = 32;
+
+#[derive(ArgEnum, Copy, Clone, Debug)]
+pub enum Format {
+    // An address per line, or CSV with an `address` header and optional `tier` and `allocation` columns
+    Csv,
+    // An array of addresses, or of objects with an `address` and optional `tier` and `allocation`
+    Json,
+}
+
+// A row as supplied, prior to validation
+pub struct Row {
+    // Line number (on which the record starts) for CSV, or position within the array for JSON, starting from 1
+    pub row: usize,
+    pub input: String,
+    address: Option<String>,
+    tier: Option<String>,
+    allocation: Option<String>,
+    // Reason the row could not be read, if any
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct ImportReport {
+    pub dry_run: bool,
+    pub accepted: usize,
+    pub duplicate: usize,
+    pub invalid: usize,
+    pub rows: Vec<RowReport>,
+}
+
+#[derive(Serialize)]
+pub struct RowReport {
+    pub row: usize,
+    pub input: String,
+    // Normalised (EIP-55 checksummed) address
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub address: Option<String>,
+    pub result: RowResult,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+#[derive(Serialize, Copy, Clone, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RowResult {
+    Accepted,
+    Duplicate,
+    Invalid,
+}
+
+impl std::fmt::Display for RowResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.pad(match self {
+            RowResult::Accepted => "accepted",
+            RowResult::Duplicate => "duplicate",
+            RowResult::Invalid => "invalid",
+        })
+    }
+}
+
+// Parses the rows from the input, failing only if the input as a whole cannot be read
+pub fn parse(format: Format, input: &str) -> crate::Result<Vec<Row>> {
+    match format {
+        Format::Csv => Ok(parse_csv(input)),
+        Format::Json => parse_json(input),
+    }
+}
+
+fn parse_csv(input: &str) -> Vec<Row> {
+    let mut rows = Vec::new();
+    // Columns of the address, tier and allocation, with the address being the first column unless there is a header
+    let mut columns = (0, None, None);
+    let mut header = true;
+    let mut lines = input.lines().enumerate();
+    while let Some((number, line)) = lines.next() {
+        let trimmed = line.trim();
+        // Skip blank lines and comments
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+
+        // Quoted fields may span lines, in which case the record continues until the quote is closed
+        let mut record = trimmed.to_string();
+        let mut error = None;
+        while record.matches('"').count() % 2 == 1 {
+            match lines.next() {
+                Some((_, line)) => {
+                    record.push('\n');
+                    record.push_str(line);
+                }
+                None => {
+                    error = Some("unterminated quoted field".to_string());
+                    break;
+                }
+            }
+        }
+        let record = record.trim_end();
+
+        let fields = csv_fields(record);
+        let position = |name: &str| {
+            fields
+                .iter()
+                .position(|field| field.eq_ignore_ascii_case(name))
+        };
+        if std::mem::take(&mut header) && error.is_none() {
+            if let Some(address) = position("address") {
+                columns = (address, position("tier"), position("allocation"));
+                continue;
+            }
+        }
+
+        let field = |column: Option<usize>| {
+            column
+                .and_then(|column| fields.get(column))
+                .filter(|field| !field.is_empty())
+                .cloned()
+        };
+        rows.push(Row {
+            row: number + 1,
+            input: record.to_string(),
+            address: field(Some(columns.0)),
+            tier: field(columns.1),
+            allocation: field(columns.2),
+            error,
+        });
+    }
+    rows
+}
+
+// Splits a CSV record into its (trimmed) fields, allowing fields to be quoted
+fn csv_fields(record: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = record.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(std::mem::take(&mut field).trim().to_string()),
+            c => field.push(c),
+        }
+    }
+    fields.push(field.trim().to_string());
+    fields
+}
+
+fn parse_json(input: &str) -> crate::Result<Vec<Row>> {
+    let values: Vec<Value> = serde_json::from_str(input)
+        .map_err(|e| Error::InvalidImport(format!("expected a JSON array: {}", e)))?;
+    let text = |value: Option<&Value>| match value {
+        Some(Value::String(value)) => Some(value.clone()),
+        Some(Value::Null) | None => None,
+        Some(value) => Some(value.to_string()),
+    };
+    Ok(values
+        .into_iter()
+        .enumerate()
+        .map(|(i, value)| {
+            let (address, tier, allocation) = match &value {
+                Value::String(address) => (Some(address.clone()), None, None),
+                Value::Object(object) => (
+                    text(object.get("address")),
+                    text(object.get("tier")),
+                    text(object.get("allocation")),
+                ),
+                value => (text(Some(value)), None, None),
+            };
+            Row {
+                row: i + 1,
+                input: value.to_string(),
+                address,
+                tier,
+                allocation,
+                error: None,
+            }
+        })
+        .collect())
+}
+
+// Validates the row, normalising the address using the same parsing as the websocket protocol
+fn validate(row: &Row) -> Result<Registration, String> {
+    if let Some(error) = &row.error {
+        return Err(error.clone());
+    }
+    let address = row.address.as_deref().ok_or("missing address")?;
+    let address = parse_address(address).map_err(|e| format!("invalid address: {}", e))?;
+    let tier = row.tier.clone();
+    if tier
+        .as_ref()
+        .is_some_and(|tier| tier.chars().count() > MAX_TIER_LENGTH)
+    {
+        return Err(format!("tier longer than {} characters", MAX_TIER_LENGTH));
+    }
+    let allocation = match &row.allocation {
+        Some(allocation) => allocation
+            .parse::<u32>()
+            .ok()
+            .filter(|allocation| (1..=i32::MAX as u32).contains(allocation))
+            .ok_or_else(|| format!("invalid allocation: {}", allocation))?,
+        None => 1,
+    };
+    Ok(Registration {
+        address,
+        tier,
+        allocation,
+    })
+}
+
+// Validates the rows and de-duplicates them against each other, returning a report of every row along with the
+// accepted entries (and the index of their report)
+fn review(rows: Vec<Row>) -> (Vec<RowReport>, Vec<(usize, Registration)>) {
+    let mut reports = Vec::with_capacity(rows.len());
+    let mut entries = Vec::new();
+    let mut seen: HashMap<H160, usize> = HashMap::new();
+    for row in rows {
+        let mut report = RowReport {
+            row: row.row,
+            input: row.input.clone(),
+            address: None,
+            result: RowResult::Invalid,
+            reason: None,
+        };
+        match validate(&row) {
+            Ok(entry) => {
+                report.address = Some(checksum(&entry.address));
+                match seen.entry(entry.address) {
+                    Entry::Occupied(first) => {
+                        report.result = RowResult::Duplicate;
+                        report.reason = Some(format!("duplicate of row {}", first.get()));
+                    }
+                    Entry::Vacant(vacant) => {
+                        vacant.insert(row.row);
+                        report.result = RowResult::Accepted;
+                        entries.push((reports.len(), entry));
+                    }
+                }
+            }
+            Err(reason) => report.reason = Some(reason),
+        }
+        reports.push(report);
+    }
+    (reports, entries)
+}
+
+// Validates and de-duplicates the rows (against each other and existing sign-ups), refusing blocked addresses, then
+// signs up the accepted rows in a single batch, regardless of status. Only the audit entry is written when a dry run.
+pub async fn import(
+    connection: &mut db::Connection,
+    rows: Vec<Row>,
+    dry_run: bool,
+    actor: &Actor,
+) -> crate::Result<ImportReport> {
+    let (mut reports, mut entries) = review(rows);
+
+    let transaction = connection
+        .transaction()
+        .await
+        .map_err(Error::DatabaseQuery)?;
+    let addresses: Vec<H160> = entries.iter().map(|(_, entry)| entry.address).collect();
+    let blocked = db::blocklist::blocked(&transaction, &addresses).await?;
+    let existing = db::vip::existing(&transaction, &addresses).await?;
+    entries.retain(|(i, entry)| {
+        if let Some(sources) = blocked.get(&entry.address) {
+            reports[*i].result = RowResult::Invalid;
+            reports[*i].reason = Some(format!("address is blocked ({})", sources));
+            return false;
+        }
+        let exists = existing.contains(&entry.address);
+        if exists {
+            reports[*i].result = RowResult::Duplicate;
+            reports[*i].reason = Some("already signed up".to_string());
+        }
+        !exists
+    });
+
+    if !dry_run {
+        let inserted =
+            db::vip::insert_all(&transaction, entries.iter().map(|(_, entry)| entry)).await?;
+        // Any not inserted were signed up concurrently
+        for (i, entry) in &entries {
+            if !inserted.contains(&entry.address) {
+                reports[*i].result = RowResult::Duplicate;
+                reports[*i].reason = Some("already signed up".to_string());
+            }
+        }
+    }
+
+    let count = |result| reports.iter().filter(|r| r.result == result).count();
+    let report = ImportReport {
+        dry_run,
+        accepted: count(RowResult::Accepted),
+        duplicate: count(RowResult::Duplicate),
+        invalid: count(RowResult::Invalid),
+        rows: reports,
+    };
+    // Dry runs are audited too, committing nothing but the audit entry
+    db::audit::record(
+        &transaction,
+        actor,
+        "sign-up.import",
+        None,
+        None,
+        Some(json!({
+            "dry_run": dry_run,
+            "rows": report.rows.len(),
+            "accepted": report.accepted,
+            "duplicate": report.duplicate,
+            "invalid": report.invalid,
+        })),
+    )
+    .await?;
+    transaction.commit().await.map_err(Error::DatabaseQuery)?;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIRST: &str = "0x5aaeb6053f3e94c9b9a09f33669435e7ef1beaed";
+    const SECOND: &str = "0xfb6916095ca1df60bb79ce92ce3ea74c37c5d359";
+
+    fn results(reports: &[RowReport]) -> Vec<(usize, RowResult)> {
+        reports
+            .iter()
+            .map(|report| (report.row, report.result))
+            .collect()
+    }
+
+    #[test]
+    fn csv_without_a_header_is_an_address_per_line() {
+        let rows = parse(
+            Format::Csv,
+            &format!("# allowlist\n{}\n\n  {}  \n", FIRST, SECOND),
+        )
+        .unwrap();
+        assert_eq!(rows.iter().map(|row| row.row).collect::<Vec<_>>(), [2, 4]);
+        assert_eq!(rows[1].address.as_deref(), Some(SECOND));
+        assert_eq!(rows[1].tier, None);
+    }
+
+    #[test]
+    fn csv_headers_select_the_columns() {
+        let rows = parse(
+            Format::Csv,
+            &format!(
+                "Tier,ADDRESS,allocation\n\"gold, \"\"vip\"\"\",{},3\n,{},\n",
+                FIRST, SECOND
+            ),
+        )
+        .unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].row, 2);
+        assert_eq!(rows[0].address.as_deref(), Some(FIRST));
+        assert_eq!(rows[0].tier.as_deref(), Some("gold, \"vip\""));
+        assert_eq!(rows[0].allocation.as_deref(), Some("3"));
+        assert_eq!(rows[1].tier, None);
+        assert_eq!(rows[1].allocation, None);
+    }
+
+    #[test]
+    fn quoted_csv_fields_may_span_lines() {
+        let rows = parse(
+            Format::Csv,
+            &format!(
+                "address,tier\n{},\"gold\nsilver\"\n{},bronze\n{},\"open",
+                FIRST, SECOND, FIRST
+            ),
+        )
+        .unwrap();
+        assert_eq!(
+            rows.iter().map(|row| row.row).collect::<Vec<_>>(),
+            [2, 4, 5]
+        );
+        assert_eq!(rows[0].tier.as_deref(), Some("gold\nsilver"));
+        assert_eq!(rows[1].tier.as_deref(), Some("bronze"));
+        assert_eq!(validate(&rows[2]).unwrap_err(), "unterminated quoted field");
+    }
+
+    #[test]
+    fn json_accepts_addresses_and_objects() {
+        let rows = parse(
+            Format::Json,
+            &format!(
+                r#"["{}", {{"address": "{}", "tier": "gold", "allocation": 2}}, 5, {{}}]"#,
+                FIRST, SECOND
+            ),
+        )
+        .unwrap();
+        assert_eq!(rows.len(), 4);
+        assert_eq!(rows[0].address.as_deref(), Some(FIRST));
+        assert_eq!(rows[1].tier.as_deref(), Some("gold"));
+        assert_eq!(rows[1].allocation.as_deref(), Some("2"));
+        assert_eq!(rows[2].address.as_deref(), Some("5"));
+        assert_eq!(rows[3].address, None);
+        assert_eq!(rows[3].row, 4);
+
+        assert!(matches!(
+            parse(Format::Json, "{}"),
+            Err(Error::InvalidImport(_))
+        ));
+    }
+
+    #[test]
+    fn rows_are_validated() {
+        let row = |address: Option<&str>, tier: Option<&str>, allocation: Option<&str>| Row {
+            row: 1,
+            input: String::new(),
+            address: address.map(str::to_string),
+            tier: tier.map(str::to_string),
+            allocation: allocation.map(str::to_string),
+            error: None,
+        };
+
+        let registration = validate(&row(Some(FIRST), Some("gold"), Some("3"))).unwrap();
+        assert_eq!(
+            checksum(&registration.address),
+            "0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed"
+        );
+        assert_eq!(registration.tier.as_deref(), Some("gold"));
+        assert_eq!(registration.allocation, 3);
+        assert_eq!(
+            validate(&row(Some(FIRST), None, None)).unwrap().allocation,
+            1
+        );
+
+        assert_eq!(
+            validate(&row(None, None, None)).unwrap_err(),
+            "missing address"
+        );
+        assert!(validate(&row(Some("0x1234"), None, None))
+            .unwrap_err()
+            .starts_with("invalid address"));
+
+        // Tiers are limited by characters, as is the column
+        let tier = "é".repeat(MAX_TIER_LENGTH);
+        assert!(validate(&row(Some(FIRST), Some(&tier), None)).is_ok());
+        assert_eq!(
+            validate(&row(Some(FIRST), Some(&format!("{}e", tier)), None)).unwrap_err(),
+            "tier longer than 32 characters"
+        );
+
+        for allocation in ["0", "-1", "2147483648", "one"] {
+            assert_eq!(
+                validate(&row(Some(FIRST), None, Some(allocation))).unwrap_err(),
+                format!("invalid allocation: {}", allocation)
+            );
+        }
+        assert_eq!(
+            validate(&row(Some(FIRST), None, Some("2147483647")))
+                .unwrap()
+                .allocation,
+            i32::MAX as u32
+        );
+    }
+
+    #[test]
+    fn rows_are_reported_with_duplicates_within_the_file() {
+        let rows = parse(
+            Format::Csv,
+            &format!(
+                "address\n{}\n{}\n{}\nnot-an-address\n",
+                FIRST,
+                SECOND,
+                FIRST.to_uppercase().replace("0X", "0x")
+            ),
+        )
+        .unwrap();
+        let (reports, entries) = review(rows);
+        assert_eq!(
+            results(&reports),
+            [
+                (2, RowResult::Accepted),
+                (3, RowResult::Accepted),
+                (4, RowResult::Duplicate),
+                (5, RowResult::Invalid),
+            ]
+        );
+        assert_eq!(reports[2].reason.as_deref(), Some("duplicate of row 2"));
+        assert!(reports[3]
+            .reason
+            .as_deref()
+            .unwrap()
+            .starts_with("invalid address"));
+        assert_eq!(
+            reports[2].address.as_deref(),
+            Some("0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed")
+        );
+        assert_eq!(entries.iter().map(|(i, _)| *i).collect::<Vec<_>>(), [0, 1]);
+    }
+}
@@ -1,62 +1,409 @@
+use crate::admission::Admission;
+use crate::config::{Backend, Config};
+use crate::handlers::{AdminApiKey, MetadataSalt};
 use crate::hub::Hub;
-use axum::{extract::Extension, routing::get, Router};
+use crate::models::{Actor, AllowlistOrder, AllowlistQuery, FingerprintField, Status};
+use crate::pow::ProofOfWork;
+use crate::rate_limit::RateLimiter;
+use crate::store::{memory::MemoryStore, SignUpStore};
+use axum::{
+    extract::Extension,
+    routing::{get, post},
+    Router,
+};
+use chrono::{DateTime, Utc};
+use clap::{ArgEnum, Parser, Subcommand};
+use primitive_types::H160;
 use std::{
-    env,
-    {net::SocketAddr, sync::Arc},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
 };
+use tokio::signal::unix::{signal, SignalKind};
 use tower_http::trace::{DefaultMakeSpan, TraceLayer};
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+use tracing_subscriber::{
+    fmt::writer::BoxMakeWriter, layer::SubscriberExt, util::SubscriberInitExt,
+};
 
+mod admission;
+mod blocklist;
+mod commands;
+mod config;
 mod db;
+mod encoding;
 mod error;
+mod export;
 mod filters;
 mod handlers;
 mod hub;
+mod import;
 mod models;
+mod pow;
+mod raffle;
+mod rate_limit;
+mod store;
 
 type Result<T> = std::result::Result<T, error::Error>;
 
-const CONNECTION_STRING: &str = "CONNECTION_STRING";
-const API_KEY: &str = "API_KEY";
+#[derive(Parser)]
+#[clap(version, about)]
+struct Cli {
+    /// Configuration file, defaulting to `CONFIG_FILE` or `metafashion.toml` if present
+    #[clap(long, global = true)]
+    config: Option<PathBuf>,
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Runs the API server, applying any pending migrations (default)
+    Serve,
+    #[clap(flatten)]
+    Database(DatabaseCommand),
+    /// Draws and verifies raffles over the sign-ups
+    Raffle {
+        #[clap(subcommand)]
+        command: RaffleCommand,
+    },
+}
+
+// Operational commands run against the database
+#[derive(Subcommand)]
+enum DatabaseCommand {
+    /// Applies pending database migrations
+    Migrate {
+        /// Lists migrations and whether they have been applied, without applying any
+        #[clap(long)]
+        status: bool,
+        /// Applies pending migrations within a transaction which is then rolled back
+        #[clap(long, conflicts_with = "status")]
+        dry_run: bool,
+    },
+    /// Reports whether sign-ups are open, opening or closing them if requested (within the SQLite store when configured)
+    Status {
+        #[clap(arg_enum)]
+        change: Option<StatusChange>,
+    },
+    /// Exports the allowlist, reporting the SHA-256 of the export
+    Export {
+        /// File to write to, defaulting to stdout, with the SHA-256 written alongside to `<output>.sha256`
+        #[clap(long, short)]
+        output: Option<PathBuf>,
+        #[clap(long, short, arg_enum, default_value = "csv")]
+        format: export::Format,
+        /// Fields to include, in order
+        #[clap(
+            long,
+            arg_enum,
+            use_value_delimiter = true,
+            default_value = "address,signed_up_at"
+        )]
+        fields: Vec<export::Field>,
+        #[clap(long, arg_enum, default_value = "signed_up_at")]
+        order: AllowlistOrder,
+        /// Orders in descending rather than ascending order
+        #[clap(long)]
+        descending: bool,
+        /// Only includes sign-ups from this time (RFC 3339) or date (UTC), inclusive
+        #[clap(long, parse(try_from_str = commands::parse_time))]
+        from: Option<DateTime<Utc>>,
+        /// Only includes sign-ups before this time (RFC 3339) or date (UTC), exclusive
+        #[clap(long, parse(try_from_str = commands::parse_time))]
+        to: Option<DateTime<Utc>>,
+    },
+    /// Imports addresses from a CSV or JSON file, or `-` for stdin, regardless of status
+    Import {
+        input: PathBuf,
+        /// Input format, defaulting to JSON for `.json` files and CSV otherwise
+        #[clap(long, short, arg_enum)]
+        format: Option<import::Format>,
+        /// Validates the input and reports the outcome without signing up any addresses
+        #[clap(long)]
+        dry_run: bool,
+        /// File to write the JSON report of every row to
+        #[clap(long)]
+        report: Option<PathBuf>,
+    },
+    /// Writes a consistent JSON snapshot of the status and all sign-ups
+    Snapshot {
+        /// File to write to, defaulting to stdout
+        #[clap(long, short)]
+        output: Option<PathBuf>,
+    },
+    /// Reports sign-up statistics
+    Stats,
+    /// Reports whether the address has signed up
+    Check {
+        #[clap(parse(try_from_str = models::parse_address))]
+        address: H160,
+    },
+    /// Removes the sign-ups of the addresses, notifying connected clients
+    Remove {
+        #[clap(required = true, parse(try_from_str = models::parse_address))]
+        addresses: Vec<H160>,
+        #[clap(long)]
+        reason: Option<String>,
+        /// Also blocks the addresses, so that they cannot sign up again
+        #[clap(long)]
+        block: bool,
+    },
+    /// Reports clusters of sign-ups sharing a fingerprint, from the metadata recorded with sign-ups
+    Clusters {
+        /// Seconds within which consecutive sign-ups sharing a fingerprint are clustered
+        #[clap(long, default_value = "3600")]
+        window: u64,
+        #[clap(long, default_value = "2")]
+        min_size: u64,
+        /// Fields compared
+        #[clap(
+            long,
+            arg_enum,
+            use_value_delimiter = true,
+            default_value = "ip,user_agent"
+        )]
+        fingerprint: Vec<FingerprintField>,
+        #[clap(long, default_value = "100")]
+        limit: u64,
+        /// Writes the clusters as JSON
+        #[clap(long)]
+        json: bool,
+    },
+    /// Manages the addresses refused sign-up
+    Blocklist {
+        #[clap(subcommand)]
+        command: BlocklistCommand,
+    },
+}
+
+#[derive(Subcommand)]
+enum BlocklistCommand {
+    /// Replaces the addresses listed by the source with those within the file (e.g. an OFAC SDN extract)
+    Load {
+        file: PathBuf,
+        /// Source to replace, defaulting to the configured `blocklist.source`
+        #[clap(long)]
+        source: Option<String>,
+    },
+    /// Blocks the address
+    Add {
+        #[clap(parse(try_from_str = models::parse_address))]
+        address: H160,
+        #[clap(long, default_value = "manual")]
+        source: String,
+        #[clap(long)]
+        reason: Option<String>,
+    },
+    /// Unblocks the address, from the source only if specified
+    Remove {
+        #[clap(parse(try_from_str = models::parse_address))]
+        address: H160,
+        #[clap(long)]
+        source: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum RaffleCommand {
+    #[clap(flatten)]
+    Recorded(RecordedRaffleCommand),
+    /// Re-runs a draw from a published snapshot, without requiring the database
+    Verify {
+        snapshot: PathBuf,
+        #[clap(long)]
+        seed: String,
+        #[clap(long)]
+        winners: usize,
+        #[clap(long, parse(try_from_str = commands::parse_time))]
+        to: Option<DateTime<Utc>>,
+        /// Fails unless the entries match the list hash recorded with the raffle
+        #[clap(long)]
+        list_hash: Option<String>,
+    },
+}
+
+// Raffle commands run against the database
+#[derive(Subcommand)]
+enum RecordedRaffleCommand {
+    /// Draws winners from the closed list, or from sign-ups before a cutoff which has passed, recording the result
+    Draw {
+        /// Value seeding the draw, such as the hash of a future block announced in advance
+        #[clap(long)]
+        seed: String,
+        #[clap(long)]
+        winners: usize,
+        /// Only enters sign-ups before this time (RFC 3339) or date (UTC)
+        #[clap(long, parse(try_from_str = commands::parse_time))]
+        to: Option<DateTime<Utc>>,
+        /// File to write the snapshot drawn from to, for publishing so that the draw can be verified
+        #[clap(long)]
+        snapshot: Option<PathBuf>,
+    },
+    /// Shows a recorded raffle
+    Show {
+        id: i64,
+        #[clap(long)]
+        json: bool,
+    },
+}
+
+#[derive(ArgEnum, Clone, Copy)]
+enum StatusChange {
+    Open,
+    Close,
+}
+
+impl From<StatusChange> for Status {
+    fn from(change: StatusChange) -> Self {
+        match change {
+            StatusChange::Open => Status::Open,
+            StatusChange::Close => Status::Closed,
+        }
+    }
+}
 
 #[tokio::main]
 async fn main() {
-    // Validate required configuration (todo: use config crate)
-    let connection_string = env::var(CONNECTION_STRING);
-    if connection_string.is_err() {
-        panic!("{} not set", CONNECTION_STRING)
-    }
-    let api_key = env::var(API_KEY);
-    if api_key.is_err() {
-        panic!("{} not set", API_KEY)
-    }
+    let cli = Cli::parse();
+    let command = cli.command.unwrap_or(Command::Serve);
+
+    // Load and validate configuration before anything else, so that problems are reported clearly
+    let config = Config::load(cli.config).and_then(|config| {
+        match command {
+            Command::Serve => config.validate()?,
+            // Verifying a raffle requires only the snapshot
+            Command::Raffle {
+                command: RaffleCommand::Verify { .. },
+            } => {}
+            #[cfg(feature = "sqlite")]
+            Command::Database(DatabaseCommand::Status { .. })
+                if matches!(config.store.backend, Backend::Sqlite) => {}
+            _ => config.validate_database()?,
+        }
+        Ok(config)
+    });
+    let config = match config {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
 
-    // Initialise logging
+    // Initialise logging, keeping stdout free for the output of operational commands
+    let writer = match command {
+        Command::Serve => BoxMakeWriter::new(std::io::stdout),
+        _ => BoxMakeWriter::new(std::io::stderr),
+    };
     tracing_subscriber::registry()
-        .with(tracing_subscriber::EnvFilter::new(
-            std::env::var("RUST_LOG").unwrap_or_else(|_| "debug,tower_http=debug".into()),
-        ))
-        .with(tracing_subscriber::fmt::layer())
+        .with(tracing_subscriber::EnvFilter::new(&config.server.log))
+        .with(tracing_subscriber::fmt::layer().with_writer(writer))
         .init();
 
-    // Create database connection pool
-    let pool = db::ConnectionPool::create(connection_string.unwrap())
-        .await
-        .expect("database connection pool cannot be created.");
+    let result = match command {
+        Command::Serve => serve(config).await,
+        Command::Raffle {
+            command:
+                RaffleCommand::Verify {
+                    snapshot,
+                    seed,
+                    winners,
+                    to,
+                    list_hash,
+                },
+        } => commands::raffle_verify(&snapshot, &seed, winners, to, list_hash.as_deref()),
+        Command::Raffle {
+            command: RaffleCommand::Recorded(command),
+        } => raffle(&config, command).await,
+        #[cfg(feature = "sqlite")]
+        Command::Database(DatabaseCommand::Status { change })
+            if matches!(config.store.backend, Backend::Sqlite) =>
+        {
+            commands::sqlite_status(&config.store.sqlite_path, change.map(Status::from)).await
+        }
+        Command::Database(command) => run(&config, command).await,
+    };
+    if let Err(e) = result {
+        tracing::error!("{}", e);
+        std::process::exit(1);
+    }
+}
 
-    // Initialise database
-    let connection = pool
-        .get_connection()
-        .await
-        .expect("could not get connection to database");
-    db::initialise(&connection)
-        .await
-        .expect("database can't be initialized");
+async fn serve(config: Config) -> Result<()> {
+    let admission = Arc::new(Admission::new(
+        config.limits.max_connections,
+        config.limits.max_connections_per_ip,
+        config.trusted_proxies()?,
+    ));
+
+    // Create database connection pool and migrate database, if configured
+    let pool = match config.database.connection_string {
+        Some(_) => {
+            let pool = database(&config).await?;
+            let mut connection = pool.get_connection().await?;
+            db::migrations::migrate(&mut connection, false).await?;
+            if let Some(file) = &config.blocklist.file {
+                load_blocklist(
+                    &pool,
+                    file,
+                    &config.blocklist.source,
+                    &Actor::system("startup"),
+                )
+                .await?;
+                reload_blocklist_on_hangup(
+                    pool.clone(),
+                    file.clone(),
+                    config.blocklist.source.clone(),
+                );
+            }
+            Some(pool)
+        }
+        None => None,
+    };
+
+    // Create sign-up store
+    let store: Arc<dyn SignUpStore> = match config.store.backend {
+        Backend::Postgres => Arc::new(pool.clone().expect("connection pool required")),
+        Backend::Memory => Arc::new(MemoryStore::new(Status::Open)),
+        Backend::Sqlite => sqlite_store(&config)?,
+    };
 
     // Create websocket hub
-    let hub = Arc::new(Hub::init(pool.clone(), api_key.unwrap()));
+    let hub = Arc::new(Hub::init(
+        store.clone(),
+        pool.clone(),
+        config.hub.api_key.clone().unwrap_or_default(),
+        config.hub.default_topics.iter().cloned().collect(),
+        RateLimiter::new(config.rate_limits()?),
+        config
+            .proof_of_work()?
+            .map(|settings| ProofOfWork::new(settings, pool.clone())),
+    ));
+    hub.start();
 
-    // build our application with some routes
+    // build our application with some routes, including the admin API if enabled
+    let admin = match config.admin.api_key.clone() {
+        Some(api_key) => {
+            let admin = Router::new()
+                .route("/connections", get(handlers::admin::connections))
+                .route("/announce", post(handlers::admin::announce));
+            // Importing and reporting requires the database
+            let admin = match pool {
+                Some(pool) => admin
+                    .route("/import", post(handlers::admin::import))
+                    .route("/export", get(handlers::admin::export))
+                    .route("/clusters", get(handlers::admin::clusters))
+                    .route("/audit", get(handlers::admin::audit))
+                    .route("/remove", post(handlers::admin::remove))
+                    .route("/sign-ups", get(handlers::admin::sign_ups))
+                    .route("/stats", get(handlers::admin::stats))
+                    .layer(Extension(pool)),
+                None => admin,
+            };
+            admin.layer(Extension(AdminApiKey(api_key)))
+        }
+        None => Router::new(),
+    };
     let app = Router::new()
         // Routes
         .route("/health", get(handlers::health))
@@ -66,19 +413,187 @@ async fn main() {
         //     get(handlers::vip::check).put(handlers::vip::sign_up),
         // )
         .route("/ws", get(handlers::websocket))
+        .nest("/admin", admin)
         // Middleware
         .layer(
             TraceLayer::new_for_http()
                 .make_span_with(DefaultMakeSpan::default().include_headers(true)),
         )
-        .layer(Extension(pool)) // Connection pool
-        .layer(Extension(hub));
+        .layer(Extension(store)) // Sign-up store
+        .layer(Extension(hub.clone()))
+        .layer(Extension(admission));
+    // Record sign-up metadata, if enabled
+    let app = match config.metadata_salt()? {
+        Some(salt) => app.layer(Extension(MetadataSalt(salt))),
+        None => app,
+    };
 
     // Finally start server
-    let addr = SocketAddr::from(([0, 0, 0, 0], 8000));
+    let addr = config.server.bind;
+    let (reconnect_delay, shutdown_timeout) = (config.reconnect_delay(), config.shutdown_timeout());
     tracing::debug!("listening on {}", addr);
     axum::Server::bind(&addr)
-        .serve(app.into_make_service())
+        .serve(app.into_make_service_with_connect_info::<SocketAddr, _>())
+        .with_graceful_shutdown(async move {
+            shutdown_signal().await;
+            hub.shutdown(reconnect_delay, shutdown_timeout).await;
+        })
         .await
         .unwrap();
+    Ok(())
+}
+
+// Creates the database connection pool, waiting for the database to become available
+async fn database(config: &Config) -> Result<db::ConnectionPool> {
+    let connection_string = config
+        .database
+        .connection_string
+        .clone()
+        .unwrap_or_default();
+    let pool =
+        db::ConnectionPool::create(connection_string, &config.database.tls, &config.pool()).await?;
+    pool.wait_until_available(config.startup_timeout()).await?;
+    Ok(pool)
+}
+
+#[cfg(feature = "sqlite")]
+fn sqlite_store(config: &Config) -> Result<Arc<dyn SignUpStore>> {
+    Ok(Arc::new(store::sqlite::SqliteStore::open(
+        &config.store.sqlite_path,
+    )?))
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn sqlite_store(_: &Config) -> Result<Arc<dyn SignUpStore>> {
+    unreachable!("sqlite store rejected by configuration validation")
+}
+
+async fn load_blocklist(
+    pool: &db::ConnectionPool,
+    file: &Path,
+    source: &str,
+    actor: &Actor,
+) -> Result<()> {
+    let change = blocklist::load(pool, file, source, actor).await?;
+    tracing::info!(
+        "loaded blocklist {}: {} added, {} removed, {} blocked",
+        file.display(),
+        change.added,
+        change.removed,
+        change.total
+    );
+    Ok(())
+}
+
+// Reloads the blocklist file on SIGHUP, retaining the current list should the reload fail
+fn reload_blocklist_on_hangup(pool: db::ConnectionPool, file: PathBuf, source: String) {
+    tokio::spawn(async move {
+        let mut hangup = signal(SignalKind::hangup()).expect("unable to listen for hangup signal");
+        while hangup.recv().await.is_some() {
+            let actor = Actor::system("sighup");
+            if let Err(e) = load_blocklist(&pool, &file, &source, &actor).await {
+                tracing::error!("unable to reload blocklist {}: {}", file.display(), e);
+            }
+        }
+    });
+}
+
+// Runs an operational command against the database
+async fn run(config: &Config, command: DatabaseCommand) -> Result<()> {
+    let pool = database(config).await?;
+    match command {
+        DatabaseCommand::Migrate { status, dry_run } => {
+            commands::migrate(&pool, status, dry_run).await
+        }
+        DatabaseCommand::Status { change } => {
+            commands::status(&pool, change.map(Status::from)).await
+        }
+        DatabaseCommand::Export {
+            output,
+            format,
+            fields,
+            order,
+            descending,
+            from,
+            to,
+        } => {
+            let query = AllowlistQuery {
+                from,
+                to,
+                order,
+                descending,
+            };
+            commands::export(&pool, &query, format, &fields, output.as_deref()).await
+        }
+        DatabaseCommand::Import {
+            input,
+            format,
+            dry_run,
+            report,
+        } => commands::import(&pool, &input, format, dry_run, report.as_deref()).await,
+        DatabaseCommand::Snapshot { output } => commands::snapshot(&pool, output.as_deref()).await,
+        DatabaseCommand::Stats => commands::stats(&pool).await,
+        DatabaseCommand::Check { address } => commands::check(&pool, address).await,
+        DatabaseCommand::Remove {
+            addresses,
+            reason,
+            block,
+        } => commands::remove(&pool, &addresses, reason.as_deref(), block).await,
+        DatabaseCommand::Clusters {
+            window,
+            min_size,
+            fingerprint,
+            limit,
+            json,
+        } => commands::clusters(&pool, &fingerprint, window, min_size, limit, json).await,
+        DatabaseCommand::Blocklist { command } => match command {
+            BlocklistCommand::Load { file, source } => {
+                let source = source.as_deref().unwrap_or(&config.blocklist.source);
+                commands::blocklist_load(&pool, &file, source).await
+            }
+            BlocklistCommand::Add {
+                address,
+                source,
+                reason,
+            } => commands::blocklist_add(&pool, address, &source, reason.as_deref()).await,
+            BlocklistCommand::Remove { address, source } => {
+                commands::blocklist_remove(&pool, address, source.as_deref()).await
+            }
+        },
+    }
+}
+
+// Runs a raffle command against the database
+async fn raffle(config: &Config, command: RecordedRaffleCommand) -> Result<()> {
+    let pool = database(config).await?;
+    match command {
+        RecordedRaffleCommand::Draw {
+            seed,
+            winners,
+            to,
+            snapshot,
+        } => commands::raffle_draw(&pool, &seed, winners, to, snapshot.as_deref()).await,
+        RecordedRaffleCommand::Show { id, json } => commands::raffle_show(&pool, id, json).await,
+    }
+}
+
+// Completes once SIGINT or SIGTERM received
+async fn shutdown_signal() {
+    let interrupt = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("unable to listen for interrupt signal");
+    };
+    let terminate = async {
+        signal(SignalKind::terminate())
+            .expect("unable to listen for terminate signal")
+            .recv()
+            .await;
+    };
+
+    tokio::select! {
+        _ = interrupt => {},
+        _ = terminate => {},
+    }
+    tracing::info!("shutdown signal received");
 }
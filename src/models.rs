@@ -1,13 +1,21 @@
 use chrono::prelude::*;
 use primitive_types::H160;
+use serde::de::{value, IntoDeserializer};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize)]
 pub struct SignUp {
     pub address: H160,
     pub signed_up_at: DateTime<Utc>,
 }
 
+// Result of a sign-up, indicating whether the address was newly signed up
+pub enum SignUpOutcome {
+    Created(SignUp),
+    Existing(SignUp),
+}
+
 #[derive(Serialize)]
 pub struct SignUps {
     pub total: u64,
@@ -15,8 +23,298 @@ pub struct SignUps {
     pub status: Status,
 }
 
+// A sign-up along with its allowlist tier and allocation
+#[derive(Serialize)]
+pub struct AllowlistEntry {
+    pub address: H160,
+    pub signed_up_at: DateTime<Utc>,
+    pub tier: Option<String>,
+    pub allocation: u32,
+    pub campaign: String,
+}
+
+// An address to be signed up along with its allowlist tier and allocation, such as when imported
+#[derive(Debug)]
+pub struct Registration {
+    pub address: H160,
+    pub tier: Option<String>,
+    pub allocation: u32,
+}
+
+// Selects allowlist entries signed up from (inclusive) and to (exclusive) the given times
+pub struct AllowlistQuery {
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub order: AllowlistOrder,
+    pub descending: bool,
+}
+
+#[derive(clap::ArgEnum, Serialize, Deserialize, Copy, Clone, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum AllowlistOrder {
+    #[clap(name = "signed_up_at")]
+    SignedUpAt,
+    #[clap(name = "address")]
+    Address,
+}
+
+// Width of the buckets sign-ups are counted in, aligned to UTC
+#[derive(clap::ArgEnum, Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Bucket {
+    #[clap(name = "minute")]
+    Minute,
+    #[clap(name = "hour")]
+    Hour,
+    #[clap(name = "day")]
+    Day,
+}
+
+impl Bucket {
+    // The unit, as understood by `date_trunc`
+    pub fn unit(&self) -> &'static str {
+        match self {
+            Bucket::Minute => "minute",
+            Bucket::Hour => "hour",
+            Bucket::Day => "day",
+        }
+    }
+
+    pub fn duration(&self) -> chrono::Duration {
+        match self {
+            Bucket::Minute => chrono::Duration::minutes(1),
+            Bucket::Hour => chrono::Duration::hours(1),
+            Bucket::Day => chrono::Duration::days(1),
+        }
+    }
+}
+
+// Counts sign-ups from (inclusive) and to (exclusive) the given times, in buckets, for the campaign if specified
+#[derive(Deserialize)]
+pub struct SeriesQuery {
+    #[serde(default = "default_bucket")]
+    pub bucket: Bucket,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub campaign: Option<String>,
+}
+
+fn default_bucket() -> Bucket {
+    Bucket::Hour
+}
+
+#[derive(Serialize)]
+pub struct SignUpSeries {
+    pub bucket: Bucket,
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+    // Sign-ups before the first bucket
+    pub before: u64,
+    pub buckets: Vec<BucketCount>,
+}
+
+#[derive(Serialize)]
+pub struct BucketCount {
+    pub start: DateTime<Utc>,
+    pub count: u64,
+    // Sign-ups up to the end of the bucket
+    pub cumulative: u64,
+    pub campaigns: BTreeMap<String, u64>,
+}
+
+// Sign-ups within the bucket currently in progress
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CurrentBucket {
+    pub start: DateTime<Utc>,
+    pub count: u64,
+}
+
+// Sign-ups within the buckets currently in progress, across all campaigns
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CurrentBuckets {
+    pub minute: CurrentBucket,
+    pub hour: CurrentBucket,
+    pub day: CurrentBucket,
+}
+
+// Searches sign-ups, a page at a time, continuing after the cursor returned with the previous page
+#[derive(Deserialize)]
+pub struct SignUpSearch {
+    // Signed up from (inclusive) and to (exclusive)
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    // Hex prefix of the address, with or without `0x`
+    pub prefix: Option<String>,
+    pub tier: Option<String>,
+    pub campaign: Option<String>,
+    #[serde(default = "default_order")]
+    pub order: AllowlistOrder,
+    #[serde(default)]
+    pub descending: bool,
+    pub limit: Option<u64>,
+    pub cursor: Option<String>,
+}
+
+fn default_order() -> AllowlistOrder {
+    AllowlistOrder::SignedUpAt
+}
+
+#[derive(Serialize)]
+pub struct SignUpPage {
+    // Sign-ups matching the search, across all pages
+    pub total: u64,
+    pub sign_ups: Vec<AllowlistEntry>,
+    // Cursor for the next page, if any
+    pub next_cursor: Option<String>,
+}
+
+// Metadata about the request which signed up an address, recorded for sybil analysis when enabled
+#[derive(Serialize, Default, Clone, Debug)]
+pub struct SignUpMetadata {
+    // Salted hash of the client IP, so that sign-ups from the same IP can be related without storing the IP
+    pub ip_hash: Option<String>,
+    pub user_agent: Option<String>,
+    pub origin: Option<String>,
+    pub utm_source: Option<String>,
+    pub utm_medium: Option<String>,
+    pub utm_campaign: Option<String>,
+    pub utm_term: Option<String>,
+    pub utm_content: Option<String>,
+    pub connection_id: Option<String>,
+}
+
+// The metadata compared when clustering sign-ups
+#[derive(clap::ArgEnum, Copy, Clone, Debug, PartialEq)]
+pub enum FingerprintField {
+    #[clap(name = "ip")]
+    Ip,
+    #[clap(name = "user_agent")]
+    UserAgent,
+    #[clap(name = "origin")]
+    Origin,
+    // The source, medium and campaign
+    #[clap(name = "utm")]
+    Utm,
+    #[clap(name = "connection")]
+    Connection,
+}
+
+// Sign-ups sharing a fingerprint, each within the window of the previous
+#[derive(Serialize)]
+pub struct Cluster {
+    pub fingerprint: serde_json::Value,
+    pub size: u64,
+    pub first_signed_up: DateTime<Utc>,
+    pub last_signed_up: DateTime<Utc>,
+    pub addresses: Vec<H160>,
+}
+
+// A raffle drawn over the sign-ups, which can be re-run from a snapshot using the seed and cutoff
+#[derive(Serialize)]
+pub struct Raffle {
+    pub id: i64,
+    pub seed: String,
+    // SHA-256 of the sorted list of addresses entered
+    pub list_hash: String,
+    pub entries: u64,
+    // Only sign-ups before the cutoff were entered, when specified
+    pub cutoff: Option<DateTime<Utc>>,
+    pub drawn_at: DateTime<Utc>,
+    // In the order drawn
+    pub winners: Vec<H160>,
+}
+
+// Outcome of removing sign-ups
+#[derive(Serialize)]
+pub struct Removal {
+    pub removed: Vec<H160>,
+    // Addresses which were not signed up
+    pub not_found: Vec<H160>,
+    // Blocklist listings added or updated
+    pub blocked: u64,
+}
+
+// Who performed an audited action, along with metadata about the request
+#[derive(Clone, Debug)]
+pub struct Actor {
+    pub name: String,
+    pub metadata: serde_json::Value,
+}
+
+impl Actor {
+    // The operator running a command, identified by their user
+    pub fn cli() -> Actor {
+        let user = std::env::var("USER").unwrap_or_else(|_| "unknown".to_string());
+        Actor {
+            name: format!("cli:{}", user),
+            metadata: serde_json::json!({
+                "host": std::env::var("HOSTNAME").ok(),
+                "command": std::env::args().skip(1).collect::<Vec<_>>().join(" "),
+            }),
+        }
+    }
+
+    // The server itself, such as when loading the blocklist
+    pub fn system(trigger: &str) -> Actor {
+        Actor {
+            name: "system".to_string(),
+            metadata: serde_json::json!({ "trigger": trigger }),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct AuditEntry {
+    pub id: i64,
+    pub occurred_at: DateTime<Utc>,
+    pub actor: String,
+    pub action: String,
+    pub target: Option<String>,
+    pub before: Option<serde_json::Value>,
+    pub after: Option<serde_json::Value>,
+    pub metadata: Option<serde_json::Value>,
+}
+
+// Filters audit entries, with the actor and action also matching any qualified by them (e.g. `blocklist` matching
+// `blocklist.add`), returning the most recent first
+#[derive(Deserialize, Default)]
+pub struct AuditQuery {
+    pub actor: Option<String>,
+    pub action: Option<String>,
+    pub target: Option<String>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    // Only entries before this id, for paging
+    pub before: Option<i64>,
+    pub limit: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct SignUpStats {
+    pub total: u64,
+    pub first_signed_up: Option<DateTime<Utc>>,
+    pub last_signed_up: Option<DateTime<Utc>>,
+    pub last_hour: u64,
+    pub last_day: u64,
+    pub status: Status,
+}
+
+// A consistent point-in-time copy of all sign-ups
+#[derive(Serialize, Deserialize)]
+pub struct Snapshot {
+    pub taken_at: DateTime<Utc>,
+    pub status: Status,
+    pub total: u64,
+    pub sign_ups: Vec<SignUp>,
+}
+
 #[derive(Serialize, Deserialize, Copy, Clone, Debug)]
 pub enum Status {
     Closed = 0,
     Open = 1,
 }
+
+// Parses an address (with or without the `0x` prefix) exactly as addresses within websocket requests are parsed
+pub fn parse_address(value: &str) -> Result<H160, value::Error> {
+    H160::deserialize(value.trim().into_deserializer())
+}
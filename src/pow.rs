@@ -0,0 +1,399 @@
+use crate::db;
+use crate::error::Error::{ChallengeRequired, InvalidChallenge};
+use chrono::{DateTime, TimeZone, Utc};
+use hmac::{Hmac, Mac};
+use primitive_types::H160;
+use rand::RngCore;
+use rustc_hex::{FromHex, ToHex};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+type HmacSha256 = Hmac<Sha256>;
+
+const VERSION: &str = "v1";
+// Period over which the sign-up rate is measured
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+// Interval at which expired redemptions are pruned
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+// A solution to a challenge, submitted along with a sign-up
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Solution {
+    pub challenge: String,
+    pub nonce: u64,
+}
+
+// A hashcash-style challenge: find a nonce such that sha256("{challenge}:{address}:{nonce}") has at least
+// `difficulty` leading zero bits, where the address is lowercase hex with the `0x` prefix
+pub struct Challenge {
+    pub challenge: String,
+    pub difficulty: u32,
+    pub expires_at: DateTime<Utc>,
+}
+
+// A verified solution, whose challenge has been claimed and is released should the sign-up fail
+pub struct Redemption {
+    id: String,
+    expires_at: DateTime<Utc>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Settings {
+    // Shared by all replicas, so that challenges issued by any replica can be verified by any other
+    pub secret: String,
+    // Leading zero bits required while the sign-up rate is below the threshold
+    pub difficulty: u32,
+    pub max_difficulty: u32,
+    // Sign-ups per minute after which the difficulty increases, by a bit each time the rate doubles
+    pub threshold: u32,
+    pub expiry: Duration,
+}
+
+// Issues and verifies stateless (HMAC-signed) challenges, recording redeemed challenges so that each can only be
+// used once. Redemptions are recorded in the database when available so that they apply across replicas.
+pub struct ProofOfWork {
+    settings: Settings,
+    pool: Option<db::ConnectionPool>,
+    rate: Mutex<Rate>,
+    redeemed: Mutex<HashMap<String, DateTime<Utc>>>,
+    pruned: Mutex<Instant>,
+}
+
+// Sign-up requests within the current and previous windows, from which a sliding window rate is estimated
+struct Rate {
+    started: Instant,
+    current: u64,
+    previous: u64,
+}
+
+impl ProofOfWork {
+    pub fn new(settings: Settings, pool: Option<db::ConnectionPool>) -> ProofOfWork {
+        ProofOfWork {
+            settings,
+            pool,
+            rate: Mutex::new(Rate {
+                started: Instant::now(),
+                current: 0,
+                previous: 0,
+            }),
+            redeemed: Mutex::default(),
+            pruned: Mutex::new(Instant::now()),
+        }
+    }
+
+    // Issues a challenge whose difficulty reflects the current sign-up rate
+    pub fn challenge(&self) -> Challenge {
+        let expires_at = Utc.timestamp(
+            Utc::now().timestamp() + self.settings.expiry.as_secs() as i64,
+            0,
+        );
+        let difficulty = self.difficulty();
+        let mut id = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut id);
+        let payload = format!(
+            "{}.{}.{}.{}",
+            VERSION,
+            expires_at.timestamp(),
+            difficulty,
+            id.to_hex::<String>()
+        );
+        let signature: String = self.mac(&payload).finalize().into_bytes().to_hex();
+        Challenge {
+            challenge: format!("{}.{}", payload, signature),
+            difficulty,
+            expires_at,
+        }
+    }
+
+    // Records a verified sign-up request, increasing the difficulty of subsequent challenges as the rate climbs
+    pub fn record_sign_up(&self) {
+        let mut rate = self.rate.lock().expect("rate poisoned");
+        let elapsed = rate.started.elapsed();
+        if elapsed >= RATE_WINDOW {
+            // Carry the count over only when the window immediately follows the last
+            rate.previous = if elapsed < RATE_WINDOW * 2 {
+                rate.current
+            } else {
+                0
+            };
+            rate.current = 0;
+            rate.started = Instant::now();
+        }
+        rate.current += 1;
+    }
+
+    // Verifies the solution is for a valid, unexpired and unredeemed challenge and sufficient for the address,
+    // atomically redeeming the challenge and returning the redemption, to be released should the sign-up fail
+    pub async fn verify(
+        &self,
+        solution: Option<&Solution>,
+        address: H160,
+    ) -> crate::Result<Redemption> {
+        let solution = solution.ok_or(ChallengeRequired)?;
+        let (payload, signature) = solution
+            .challenge
+            .rsplit_once('.')
+            .ok_or(InvalidChallenge("malformed"))?;
+        let signature: Vec<u8> = signature
+            .from_hex()
+            .map_err(|_| InvalidChallenge("malformed"))?;
+        self.mac(payload)
+            .verify_slice(&signature)
+            .map_err(|_| InvalidChallenge("invalid signature"))?;
+
+        let parts: Vec<&str> = payload.split('.').collect();
+        let (expires_at, difficulty, id) = match parts[..] {
+            [VERSION, expires_at, difficulty, id] => (
+                expires_at
+                    .parse::<i64>()
+                    .map_err(|_| InvalidChallenge("malformed"))?,
+                difficulty
+                    .parse::<u32>()
+                    .map_err(|_| InvalidChallenge("malformed"))?,
+                id,
+            ),
+            _ => return Err(InvalidChallenge("malformed")),
+        };
+        let expires_at = Utc.timestamp(expires_at, 0);
+        if expires_at <= Utc::now() {
+            return Err(InvalidChallenge("expired"));
+        }
+
+        let hash = Sha256::digest(
+            format!("{}:{:#x}:{}", solution.challenge, address, solution.nonce).as_bytes(),
+        );
+        if leading_zeros(&hash) < difficulty {
+            return Err(InvalidChallenge("insufficient work"));
+        }
+
+        // Claim the challenge as part of verifying it, so that concurrent sign-ups cannot both use the same solution
+        let redemption = Redemption {
+            id: id.to_string(),
+            expires_at,
+        };
+        if !self.redeem(&redemption).await? {
+            return Err(InvalidChallenge("already used"));
+        }
+        Ok(redemption)
+    }
+
+    // Releases a claimed challenge, so that a sign-up which failed can be retried with the same solution
+    pub async fn release(&self, redemption: Redemption) -> crate::Result<()> {
+        if let Some(pool) = &self.pool {
+            let connection = pool.get_connection().await?;
+            return db::pow::release(&connection, &redemption.id).await;
+        }
+        self.redeemed
+            .lock()
+            .expect("redeemed poisoned")
+            .remove(&redemption.id);
+        Ok(())
+    }
+
+    // The difficulty for the estimated sign-up rate over the last window
+    fn difficulty(&self) -> u32 {
+        let rate = {
+            let rate = self.rate.lock().expect("rate poisoned");
+            let elapsed = rate.started.elapsed().as_secs_f64() / RATE_WINDOW.as_secs_f64();
+            match elapsed {
+                e if e >= 2.0 => 0.0,
+                e if e >= 1.0 => rate.current as f64 * (2.0 - e),
+                e => rate.previous as f64 * (1.0 - e) + rate.current as f64,
+            }
+        };
+        let threshold = self.settings.threshold as f64;
+        let increase = match rate >= threshold {
+            true => (rate / threshold).log2().floor() as u32 + 1,
+            false => 0,
+        };
+        (self.settings.difficulty + increase).min(self.settings.max_difficulty)
+    }
+
+    fn mac(&self, payload: &str) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(self.settings.secret.as_bytes())
+            .expect("hmac accepts keys of any length");
+        mac.update(payload.as_bytes());
+        mac
+    }
+
+    // Records the challenge as redeemed, returning whether it had not already been redeemed
+    async fn redeem(&self, redemption: &Redemption) -> crate::Result<bool> {
+        let prune = {
+            let mut pruned = self.pruned.lock().expect("pruned poisoned");
+            let prune = pruned.elapsed() >= PRUNE_INTERVAL;
+            if prune {
+                *pruned = Instant::now();
+            }
+            prune
+        };
+
+        if let Some(pool) = &self.pool {
+            let connection = pool.get_connection().await?;
+            if prune {
+                db::pow::prune(&connection).await?;
+            }
+            return db::pow::redeem(&connection, &redemption.id, redemption.expires_at).await;
+        }
+
+        let mut redeemed = self.redeemed.lock().expect("redeemed poisoned");
+        if prune {
+            let now = Utc::now();
+            redeemed.retain(|_, expires_at| *expires_at > now);
+        }
+        Ok(redeemed
+            .insert(redemption.id.clone(), redemption.expires_at)
+            .is_none())
+    }
+}
+
+fn leading_zeros(hash: &[u8]) -> u32 {
+    let mut zeros = 0;
+    for byte in hash {
+        zeros += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    zeros
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+
+    fn proof_of_work(difficulty: u32, expiry: Duration) -> ProofOfWork {
+        ProofOfWork::new(
+            Settings {
+                secret: "secret".to_string(),
+                difficulty,
+                max_difficulty: 24,
+                threshold: 100,
+                expiry,
+            },
+            None,
+        )
+    }
+
+    fn solve(challenge: &Challenge, address: H160) -> Solution {
+        (0..)
+            .map(|nonce| Solution {
+                challenge: challenge.challenge.clone(),
+                nonce,
+            })
+            .find(|solution| {
+                let hash = Sha256::digest(
+                    format!("{}:{:#x}:{}", solution.challenge, address, solution.nonce).as_bytes(),
+                );
+                leading_zeros(&hash) >= challenge.difficulty
+            })
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn solutions_are_verified_once() {
+        let proof_of_work = proof_of_work(8, Duration::from_secs(60));
+        let address = H160::from_low_u64_be(1);
+        let challenge = proof_of_work.challenge();
+        assert_eq!(challenge.difficulty, 8);
+        let solution = solve(&challenge, address);
+
+        assert!(matches!(
+            proof_of_work.verify(None, address).await,
+            Err(Error::ChallengeRequired)
+        ));
+        let redemption = proof_of_work
+            .verify(Some(&solution), address)
+            .await
+            .unwrap();
+        assert!(matches!(
+            proof_of_work.verify(Some(&solution), address).await,
+            Err(Error::InvalidChallenge("already used"))
+        ));
+
+        // A released challenge can be used again, as the sign-up it was claimed for failed
+        proof_of_work.release(redemption).await.unwrap();
+        assert!(proof_of_work.verify(Some(&solution), address).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn expired_challenges_are_rejected() {
+        let proof_of_work = proof_of_work(0, Duration::ZERO);
+        let address = H160::from_low_u64_be(1);
+        let solution = solve(&proof_of_work.challenge(), address);
+        assert!(matches!(
+            proof_of_work.verify(Some(&solution), address).await,
+            Err(Error::InvalidChallenge("expired"))
+        ));
+    }
+
+    #[tokio::test]
+    async fn challenges_with_invalid_signatures_are_rejected() {
+        let proof_of_work = proof_of_work(0, Duration::from_secs(60));
+        let address = H160::from_low_u64_be(1);
+        let challenge = proof_of_work.challenge();
+
+        // Lowering the difficulty invalidates the signature
+        let (payload, signature) = challenge.challenge.rsplit_once('.').unwrap();
+        let forged = Solution {
+            challenge: format!("{}.{}", payload.replacen(".0.", ".1.", 1), signature),
+            nonce: 0,
+        };
+        assert!(matches!(
+            proof_of_work.verify(Some(&forged), address).await,
+            Err(Error::InvalidChallenge("invalid signature"))
+        ));
+
+        // As does a challenge signed with another secret
+        let mut other = proof_of_work.settings.clone();
+        other.secret = "other".to_string();
+        let other = ProofOfWork::new(other, None);
+        let solution = solve(&other.challenge(), address);
+        assert!(matches!(
+            proof_of_work.verify(Some(&solution), address).await,
+            Err(Error::InvalidChallenge("invalid signature"))
+        ));
+    }
+
+    #[tokio::test]
+    async fn insufficient_work_is_rejected() {
+        let proof_of_work = proof_of_work(16, Duration::from_secs(60));
+        let address = H160::from_low_u64_be(1);
+        let challenge = proof_of_work.challenge();
+        let solution = solve(&challenge, address);
+
+        // The solution is only valid for the address it was found for
+        let other = (1..)
+            .map(H160::from_low_u64_be)
+            .find(|other| {
+                let hash = Sha256::digest(
+                    format!("{}:{:#x}:{}", solution.challenge, other, solution.nonce).as_bytes(),
+                );
+                leading_zeros(&hash) < challenge.difficulty
+            })
+            .unwrap();
+        assert!(matches!(
+            proof_of_work.verify(Some(&solution), other).await,
+            Err(Error::InvalidChallenge("insufficient work"))
+        ));
+        assert!(proof_of_work.verify(Some(&solution), address).await.is_ok());
+    }
+
+    #[test]
+    fn difficulty_increases_with_the_sign_up_rate() {
+        let proof_of_work = proof_of_work(8, Duration::from_secs(60));
+        for _ in 0..99 {
+            proof_of_work.record_sign_up();
+        }
+        assert_eq!(proof_of_work.difficulty(), 8);
+        proof_of_work.record_sign_up();
+        assert_eq!(proof_of_work.difficulty(), 9);
+        for _ in 0..100 {
+            proof_of_work.record_sign_up();
+        }
+        assert_eq!(proof_of_work.difficulty(), 10);
+    }
+}
@@ -0,0 +1,167 @@
+use crate::error::Error::InvalidRaffle;
+use crate::models::{Snapshot, Status};
+use chrono::{DateTime, Utc};
+use primitive_types::H160;
+use rustc_hex::ToHex;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeSet;
+
+// The outcome of a draw, which depends only upon the seed and the list of addresses entered
+pub struct Draw {
+    pub list_hash: String,
+    pub entries: u64,
+    pub winners: Vec<H160>,
+}
+
+// The addresses entered into a raffle from the snapshot: those signed up before the cutoff, if any. The list must be
+// frozen (either closed or only including sign-ups before a cutoff which has passed) so that the draw is fair.
+pub fn entries(snapshot: &Snapshot, cutoff: Option<DateTime<Utc>>) -> crate::Result<Vec<H160>> {
+    let frozen = match cutoff {
+        Some(cutoff) => cutoff <= snapshot.taken_at,
+        None => matches!(snapshot.status, Status::Closed),
+    };
+    if !frozen {
+        return Err(InvalidRaffle(
+            "the list must be closed, or a cutoff which has passed specified".into(),
+        ));
+    }
+    Ok(snapshot
+        .sign_ups
+        .iter()
+        .filter(|sign_up| cutoff.is_none_or(|cutoff| sign_up.signed_up_at < cutoff))
+        .map(|sign_up| sign_up.address)
+        .collect())
+}
+
+// Draws the winners from the addresses, in an order determined only by the seed and the list. The list is sorted and
+// hashed, and the winners then selected by a partial Fisher-Yates shuffle of the sorted list, using successive
+// sha256("{seed}:{list_hash}:{counter}") digests (the first 8 bytes as a big-endian integer) as the source of
+// randomness, discarding values which would bias the selection.
+pub fn draw(seed: &str, addresses: &[H160], winners: usize) -> crate::Result<Draw> {
+    if seed.trim().is_empty() || winners == 0 {
+        return Err(InvalidRaffle(
+            "a seed and at least one winner are required".into(),
+        ));
+    }
+    let mut list: Vec<H160> = addresses
+        .iter()
+        .copied()
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect();
+    let list_hash = list_hash(&list);
+    let entries = list.len() as u64;
+
+    let mut random = Random {
+        seed: format!("{}:{}", seed.trim(), list_hash),
+        counter: 0,
+    };
+    let winners = winners.min(list.len());
+    for i in 0..winners {
+        let j = i + random.below((list.len() - i) as u64) as usize;
+        list.swap(i, j);
+    }
+    list.truncate(winners);
+
+    Ok(Draw {
+        list_hash,
+        entries,
+        winners: list,
+    })
+}
+
+// SHA-256 of the sorted addresses, as lowercase hex with the `0x` prefix, one per line
+pub fn list_hash(sorted: &[H160]) -> String {
+    let mut hasher = Sha256::new();
+    for address in sorted {
+        hasher.update(format!("{:#x}\n", address).as_bytes());
+    }
+    hasher.finalize().to_hex()
+}
+
+struct Random {
+    seed: String,
+    counter: u64,
+}
+
+impl Random {
+    fn next(&mut self) -> u64 {
+        let digest = Sha256::digest(format!("{}:{}", self.seed, self.counter).as_bytes());
+        self.counter += 1;
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(&digest[..8]);
+        u64::from_be_bytes(bytes)
+    }
+
+    // A value uniformly distributed below the bound
+    fn below(&mut self, bound: u64) -> u64 {
+        let limit = u64::MAX - u64::MAX % bound;
+        loop {
+            let value = self.next();
+            if value < limit {
+                return value % bound;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::draw;
+    use primitive_types::H160;
+
+    const SEED: &str = "0x5c9b2d8f3a1e7b6c4d0f9e8a7b6c5d4e3f2a1b0c9d8e7f6a5b4c3d2e1f0a9b8c";
+
+    fn addresses(values: &[u64]) -> Vec<H160> {
+        values.iter().copied().map(H160::from_low_u64_be).collect()
+    }
+
+    // Published results must remain reproducible, so the exact outcome of a draw is pinned
+    #[test]
+    fn draws_are_reproducible() {
+        let list = addresses(&[10, 9, 8, 7, 6, 5, 4, 3, 2, 1]);
+        let draw = draw(SEED, &list, 3).unwrap();
+        assert_eq!(
+            draw.list_hash,
+            "e4ed49c3a524894778ee1f73e886fe767e7df001620fde678b6dc850c7f4853a"
+        );
+        assert_eq!(draw.entries, 10);
+        assert_eq!(draw.winners, addresses(&[3, 6, 9]));
+
+        // The order in which addresses are listed does not affect the draw, nor does whitespace around the seed
+        let mut reordered = list.clone();
+        reordered.reverse();
+        let redrawn = super::draw(&format!(" {}\n", SEED), &reordered, 3).unwrap();
+        assert_eq!(redrawn.list_hash, draw.list_hash);
+        assert_eq!(redrawn.winners, draw.winners);
+    }
+
+    #[test]
+    fn every_entry_wins_when_winners_exceed_entries() {
+        let draw = draw(SEED, &addresses(&[3, 1, 2, 1]), 5).unwrap();
+        assert_eq!(
+            draw.list_hash,
+            "a39ab460ed1b41928fa2fe9b47c41b735d964a29d62f4f1fbdfa784d57f6d835"
+        );
+        assert_eq!(draw.entries, 3);
+        assert_eq!(draw.winners, addresses(&[3, 2, 1]));
+    }
+
+    #[test]
+    fn empty_lists_have_no_winners() {
+        let draw = draw(SEED, &[], 3).unwrap();
+        assert_eq!(
+            draw.list_hash,
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(draw.entries, 0);
+        assert!(draw.winners.is_empty());
+    }
+
+    #[test]
+    fn seeds_and_winners_are_required() {
+        let list = addresses(&[1, 2, 3]);
+        assert!(draw(" ", &list, 1).is_err());
+        assert!(draw(SEED, &list, 0).is_err());
+    }
+}
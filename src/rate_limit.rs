@@ -0,0 +1,400 @@
+use crate::error::Error;
+use primitive_types::H160;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Number of buckets tracked per scope before full buckets are pruned
+const PRUNE_THRESHOLD: usize = 10_000;
+// Minimum interval between prunes of a scope, so that the buckets are not scanned on every request while above the
+// threshold
+const PRUNE_INTERVAL: Duration = Duration::from_secs(10);
+
+// A token bucket limit, allowing a burst of `capacity` requests which is refilled evenly over `period`
+#[derive(Deserialize, Copy, Clone, Debug)]
+#[serde(try_from = "String")]
+pub struct Limit {
+    pub capacity: u32,
+    pub period: Duration,
+}
+
+impl Limit {
+    pub const fn new(capacity: u32, seconds: u64) -> Limit {
+        Limit {
+            capacity,
+            period: Duration::from_secs(seconds),
+        }
+    }
+
+    fn refill_rate(&self) -> f64 {
+        self.capacity as f64 / self.period.as_secs_f64()
+    }
+}
+
+// Parses a limit in the form `capacity/seconds`
+impl FromStr for Limit {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::InvalidRateLimit(value.to_string());
+        let (capacity, seconds) = value.split_once('/').ok_or_else(invalid)?;
+        let capacity: u32 = capacity.trim().parse().map_err(|_| invalid())?;
+        let seconds: u64 = seconds.trim().parse().map_err(|_| invalid())?;
+        if capacity == 0 || seconds == 0 {
+            return Err(invalid());
+        }
+        Ok(Limit::new(capacity, seconds))
+    }
+}
+
+impl TryFrom<String> for Limit {
+    type Error = Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn full(limit: &Limit, now: Instant) -> Bucket {
+        Bucket {
+            tokens: limit.capacity as f64,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, limit: &Limit, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.refill_rate()).min(limit.capacity as f64);
+        self.updated = now;
+    }
+
+    // Takes a token, otherwise returning the time until one becomes available
+    fn take(&mut self, limit: &Limit, now: Instant) -> Result<(), Duration> {
+        self.refill(limit, now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Ok(());
+        }
+        Err(Duration::from_secs_f64(
+            (1.0 - self.tokens) / limit.refill_rate(),
+        ))
+    }
+
+    // Returns a token taken for a request which was then rejected
+    fn refund(&mut self, limit: &Limit) {
+        self.tokens = (self.tokens + 1.0).min(limit.capacity as f64);
+    }
+}
+
+// The scopes over which requests are limited
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Scope {
+    Connection,
+    Ip,
+    Address,
+}
+
+impl Scope {
+    pub const ALL: [Scope; 3] = [Scope::Connection, Scope::Ip, Scope::Address];
+}
+
+impl FromStr for Scope {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        Scope::ALL
+            .into_iter()
+            .find(|scope| scope.to_string() == value)
+            .ok_or(())
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Scope::Connection => write!(f, "connection"),
+            Scope::Ip => write!(f, "ip"),
+            Scope::Address => write!(f, "address"),
+        }
+    }
+}
+
+// Limits for each request type and scope, along with the limit on violations before a client is disconnected
+#[derive(Clone, Debug)]
+pub struct RateLimits {
+    limits: HashMap<(&'static str, Scope), Limit>,
+    pub violations: Limit,
+}
+
+impl RateLimits {
+    pub fn set(&mut self, request: &'static str, scope: Scope, limit: Limit) {
+        self.limits.insert((request, scope), limit);
+    }
+
+    fn get(&self, request: &'static str, scope: Scope) -> Option<&Limit> {
+        self.limits.get(&(request, scope))
+    }
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        let mut limits = RateLimits {
+            limits: HashMap::new(),
+            violations: Limit::new(10, 60),
+        };
+        limits.set("sign-up", Scope::Connection, Limit::new(5, 60));
+        limits.set("sign-up", Scope::Ip, Limit::new(20, 60));
+        limits.set("sign-up", Scope::Address, Limit::new(5, 60));
+        limits.set("check", Scope::Connection, Limit::new(30, 60));
+        limits.set("check", Scope::Ip, Limit::new(120, 60));
+        limits.set("check", Scope::Address, Limit::new(30, 60));
+        limits.set("subscribe", Scope::Connection, Limit::new(30, 60));
+        limits.set("unsubscribe", Scope::Connection, Limit::new(30, 60));
+        limits.set("challenge", Scope::Connection, Limit::new(10, 60));
+        limits.set("challenge", Scope::Ip, Limit::new(60, 60));
+        limits.set("resume", Scope::Connection, Limit::new(10, 60));
+        limits.set("resume", Scope::Ip, Limit::new(60, 60));
+        limits
+    }
+}
+
+// Buckets for a single connection
+#[derive(Default)]
+pub struct ConnectionBuckets {
+    requests: HashMap<&'static str, Bucket>,
+    violations: Option<Bucket>,
+}
+
+// Rate limiter for hub requests, shared by all connections
+pub struct RateLimiter {
+    limits: RateLimits,
+    ips: Mutex<Buckets<IpAddr>>,
+    addresses: Mutex<Buckets<H160>>,
+}
+
+// Buckets of a scope by request and key, along with when they were last pruned
+struct Buckets<K> {
+    buckets: HashMap<(&'static str, K), Bucket>,
+    pruned: Option<Instant>,
+}
+
+impl<K> Default for Buckets<K> {
+    fn default() -> Self {
+        Buckets {
+            buckets: HashMap::new(),
+            pruned: None,
+        }
+    }
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits) -> RateLimiter {
+        RateLimiter {
+            limits,
+            ips: Mutex::default(),
+            addresses: Mutex::default(),
+        }
+    }
+
+    // Takes a token for the request from each applicable scope, returning an error if any scope is exhausted
+    pub fn check(
+        &self,
+        request: &'static str,
+        connection: &mut ConnectionBuckets,
+        ip: IpAddr,
+        address: Option<H160>,
+    ) -> crate::Result<()> {
+        let now = Instant::now();
+        let throttled = |scope, retry_after| Error::RateLimited { scope, retry_after };
+
+        let connection_limit = self.limits.get(request, Scope::Connection);
+        if let Some(limit) = connection_limit {
+            connection
+                .requests
+                .entry(request)
+                .or_insert_with(|| Bucket::full(limit, now))
+                .take(limit, now)
+                .map_err(|retry_after| throttled(Scope::Connection, retry_after))?;
+        }
+        // Tokens already taken from other scopes are refunded when a scope is exhausted, as the request is rejected
+        let mut refund_connection = || {
+            if let (Some(limit), Some(bucket)) =
+                (connection_limit, connection.requests.get_mut(request))
+            {
+                bucket.refund(limit);
+            }
+        };
+        if let Err(retry_after) = take(&self.ips, &self.limits, Scope::Ip, request, ip, now) {
+            refund_connection();
+            return Err(throttled(Scope::Ip, retry_after));
+        }
+        if let Some(address) = address {
+            if let Err(retry_after) = take(
+                &self.addresses,
+                &self.limits,
+                Scope::Address,
+                request,
+                address,
+                now,
+            ) {
+                refund_connection();
+                refund(&self.ips, &self.limits, Scope::Ip, request, ip);
+                return Err(throttled(Scope::Address, retry_after));
+            }
+        }
+        Ok(())
+    }
+
+    // Records a violation of the limits, returning whether the connection has exceeded the violations allowed
+    pub fn violation(&self, connection: &mut ConnectionBuckets) -> bool {
+        let now = Instant::now();
+        let limit = &self.limits.violations;
+        connection
+            .violations
+            .get_or_insert_with(|| Bucket::full(limit, now))
+            .take(limit, now)
+            .is_err()
+    }
+}
+
+// Takes a token from the bucket for the request and key, creating the bucket if required
+fn take<K: Eq + Hash>(
+    buckets: &Mutex<Buckets<K>>,
+    limits: &RateLimits,
+    scope: Scope,
+    request: &'static str,
+    key: K,
+    now: Instant,
+) -> Result<(), Duration> {
+    let limit = match limits.get(request, scope) {
+        Some(limit) => limit,
+        None => return Ok(()),
+    };
+    let mut buckets = buckets.lock().expect("rate limit buckets poisoned");
+
+    // Prune buckets which have since refilled, as they are equivalent to new buckets
+    let prune = buckets
+        .pruned
+        .is_none_or(|pruned| now.saturating_duration_since(pruned) >= PRUNE_INTERVAL);
+    if buckets.buckets.len() >= PRUNE_THRESHOLD && prune {
+        buckets.buckets.retain(|(request, _), bucket| {
+            limits
+                .get(request, scope)
+                .is_some_and(|limit| now.saturating_duration_since(bucket.updated) < limit.period)
+        });
+        buckets.pruned = Some(now);
+    }
+
+    buckets
+        .buckets
+        .entry((request, key))
+        .or_insert_with(|| Bucket::full(limit, now))
+        .take(limit, now)
+}
+
+// Refunds a token to the bucket for the request and key, if it still exists
+fn refund<K: Eq + Hash>(
+    buckets: &Mutex<Buckets<K>>,
+    limits: &RateLimits,
+    scope: Scope,
+    request: &'static str,
+    key: K,
+) {
+    if let Some(limit) = limits.get(request, scope) {
+        let mut buckets = buckets.lock().expect("rate limit buckets poisoned");
+        if let Some(bucket) = buckets.buckets.get_mut(&(request, key)) {
+            bucket.refund(limit);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejected_requests_do_not_consume_other_scopes() {
+        let mut limits = RateLimits::default();
+        limits.set("sign-up", Scope::Connection, Limit::new(2, 60));
+        limits.set("sign-up", Scope::Ip, Limit::new(10, 60));
+        limits.set("sign-up", Scope::Address, Limit::new(1, 60));
+        let limiter = RateLimiter::new(limits);
+        let mut connection = ConnectionBuckets::default();
+        let ip = IpAddr::from([127, 0, 0, 1]);
+        let (first, second) = (H160::from_low_u64_be(1), H160::from_low_u64_be(2));
+
+        assert!(limiter
+            .check("sign-up", &mut connection, ip, Some(first))
+            .is_ok());
+        // The address is exhausted, so the connection and IP tokens are refunded
+        for _ in 0..5 {
+            assert!(matches!(
+                limiter.check("sign-up", &mut connection, ip, Some(first)),
+                Err(Error::RateLimited {
+                    scope: Scope::Address,
+                    ..
+                })
+            ));
+        }
+        assert!(limiter
+            .check("sign-up", &mut connection, ip, Some(second))
+            .is_ok());
+        assert!(matches!(
+            limiter.check("sign-up", &mut connection, ip, None),
+            Err(Error::RateLimited {
+                scope: Scope::Connection,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn buckets_are_pruned_at_most_once_per_interval() {
+        let mut limits = RateLimits::default();
+        limits.set("check", Scope::Ip, Limit::new(1, 60));
+        let limiter = RateLimiter::new(limits);
+        let ip = |i: usize| IpAddr::from((i as u32).to_be_bytes());
+        let now = Instant::now();
+        let take = |i, now| {
+            take(
+                &limiter.ips,
+                &limiter.limits,
+                Scope::Ip,
+                "check",
+                ip(i),
+                now,
+            )
+        };
+        let len = || limiter.ips.lock().unwrap().buckets.len();
+
+        for i in 0..PRUNE_THRESHOLD {
+            assert!(take(i, now).is_ok());
+        }
+        // Every bucket has refilled, so all are pruned once the threshold is reached
+        let later = now + Duration::from_secs(60);
+        assert!(take(PRUNE_THRESHOLD, later).is_ok());
+        assert_eq!(len(), 1);
+
+        // Buckets added since are not pruned again until the interval has passed
+        for i in 0..PRUNE_THRESHOLD {
+            assert!(take(i, later).is_ok());
+        }
+        let refilled = later + Duration::from_secs(120);
+        assert!(take(PRUNE_THRESHOLD + 1, later + PRUNE_INTERVAL / 2).is_ok());
+        assert_eq!(len(), PRUNE_THRESHOLD + 2);
+        assert!(take(PRUNE_THRESHOLD + 2, refilled).is_ok());
+        assert_eq!(len(), 1);
+    }
+}
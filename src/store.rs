@@ -0,0 +1,357 @@
+use crate::db;
+use crate::models::{SignUpMetadata, SignUpOutcome, SignUps};
+use axum::async_trait;
+use primitive_types::H160;
+
+// Storage of VIP sign-ups, allowing the backend to be selected by configuration
+#[async_trait]
+pub trait SignUpStore: Send + Sync {
+    // Returns whether the address has signed up
+    async fn check(&self, address: H160) -> crate::Result<bool>;
+    // Signs up the address atomically, returning any existing sign-up even once the list is closed. Any metadata is
+    // recorded with a new sign-up where the store supports it.
+    async fn sign_up(
+        &self,
+        address: H160,
+        metadata: Option<&SignUpMetadata>,
+    ) -> crate::Result<SignUpOutcome>;
+    async fn total(&self) -> crate::Result<SignUps>;
+    async fn healthy(&self) -> crate::Result<()>;
+}
+
+#[async_trait]
+impl SignUpStore for db::ConnectionPool {
+    async fn check(&self, address: H160) -> crate::Result<bool> {
+        let connection = self.get_connection().await?;
+        db::vip::check(&connection, address).await
+    }
+
+    async fn sign_up(
+        &self,
+        address: H160,
+        metadata: Option<&SignUpMetadata>,
+    ) -> crate::Result<SignUpOutcome> {
+        let mut connection = self.get_connection().await?;
+        db::vip::sign_up(&mut connection, address, metadata).await
+    }
+
+    async fn total(&self) -> crate::Result<SignUps> {
+        let connection = self.get_connection().await?;
+        db::vip::total(&connection).await
+    }
+
+    async fn healthy(&self) -> crate::Result<()> {
+        let connection = self.get_connection().await?;
+        db::healthy(&connection).await
+    }
+}
+
+pub mod memory {
+    use crate::error::Error::VIPSignupClosed;
+    use crate::models::{SignUp, SignUpMetadata, SignUpOutcome, SignUps, Status};
+    use crate::store::SignUpStore;
+    use axum::async_trait;
+    use chrono::{DateTime, Utc};
+    use primitive_types::H160;
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    // Stores sign-ups in memory only, for tests and local development
+    pub struct MemoryStore {
+        state: Mutex<State>,
+    }
+
+    struct State {
+        status: Status,
+        sign_ups: HashMap<H160, DateTime<Utc>>,
+    }
+
+    impl MemoryStore {
+        pub fn new(status: Status) -> MemoryStore {
+            MemoryStore {
+                state: Mutex::new(State {
+                    status,
+                    sign_ups: HashMap::new(),
+                }),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl SignUpStore for MemoryStore {
+        async fn check(&self, address: H160) -> crate::Result<bool> {
+            let state = self.state.lock().expect("store poisoned");
+            Ok(state.sign_ups.contains_key(&address))
+        }
+
+        async fn sign_up(
+            &self,
+            address: H160,
+            _metadata: Option<&SignUpMetadata>,
+        ) -> crate::Result<SignUpOutcome> {
+            let mut state = self.state.lock().expect("store poisoned");
+            if let Some(signed_up_at) = state.sign_ups.get(&address) {
+                return Ok(SignUpOutcome::Existing(SignUp {
+                    address,
+                    signed_up_at: *signed_up_at,
+                }));
+            }
+            if matches!(state.status, Status::Closed) {
+                return Err(VIPSignupClosed);
+            }
+
+            let signed_up_at = Utc::now();
+            state.sign_ups.insert(address, signed_up_at);
+            Ok(SignUpOutcome::Created(SignUp {
+                address,
+                signed_up_at,
+            }))
+        }
+
+        async fn total(&self) -> crate::Result<SignUps> {
+            let state = self.state.lock().expect("store poisoned");
+            Ok(SignUps {
+                total: state.sign_ups.len() as u64,
+                last_signed_up: state.sign_ups.values().max().copied(),
+                status: state.status,
+            })
+        }
+
+        async fn healthy(&self) -> crate::Result<()> {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(feature = "sqlite")]
+pub mod sqlite {
+    use crate::error::Error::VIPSignupClosed;
+    use crate::models::{SignUp, SignUpMetadata, SignUpOutcome, SignUps, Status};
+    use crate::store::SignUpStore;
+    use axum::async_trait;
+    use chrono::{DateTime, Utc};
+    use primitive_types::H160;
+    use rusqlite::{params, Connection, OptionalExtension, TransactionBehavior};
+    use std::path::Path;
+    use std::sync::{Arc, Mutex};
+
+    const SCHEMA: &str = "
+        CREATE TABLE IF NOT EXISTS vip
+        (
+            status BOOLEAN NOT NULL
+        );
+
+        CREATE TABLE IF NOT EXISTS vip_signups
+        (
+            address VARCHAR (40) PRIMARY KEY NOT NULL,
+            signed_up_at TEXT NOT NULL
+        );";
+    const CHECK_STATUS_QUERY: &str = "SELECT status FROM vip";
+    const CHECK_SIGNUP_QUERY: &str = "SELECT address FROM vip_signups WHERE address = ?1";
+    const GET_SIGNUP_QUERY: &str = "SELECT signed_up_at FROM vip_signups WHERE address = ?1";
+    const SIGNUP_COMMAND: &str = "INSERT INTO vip_signups (address, signed_up_at) VALUES (?1, ?2)
+        ON CONFLICT (address) DO NOTHING";
+    const TOTAL_SIGNUPS_QUERY: &str = "SELECT COUNT(*), MAX(signed_up_at) FROM vip_signups";
+    const CLEAR_STATUS_COMMAND: &str = "DELETE FROM vip";
+    const SET_STATUS_COMMAND: &str = "INSERT INTO vip (status) VALUES (?1)";
+
+    // Stores sign-ups in a SQLite database file, for small single replica deployments
+    pub struct SqliteStore {
+        connection: Arc<Mutex<Connection>>,
+    }
+
+    impl SqliteStore {
+        pub fn open(path: impl AsRef<Path>) -> crate::Result<SqliteStore> {
+            let connection = Connection::open(path)?;
+            connection.execute_batch(SCHEMA)?;
+            Ok(SqliteStore {
+                connection: Arc::new(Mutex::new(connection)),
+            })
+        }
+
+        // Opens or closes sign-ups, which are closed until first opened
+        pub async fn set_status(&self, status: Status) -> crate::Result<()> {
+            self.run(move |connection| {
+                let transaction =
+                    connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+                transaction.execute(CLEAR_STATUS_COMMAND, [])?;
+                transaction.execute(SET_STATUS_COMMAND, [matches!(status, Status::Open)])?;
+                transaction.commit()?;
+                Ok(())
+            })
+            .await
+        }
+
+        // Runs the blocking operation against the database on the blocking thread pool
+        async fn run<T, F>(&self, operation: F) -> crate::Result<T>
+        where
+            T: Send + 'static,
+            F: FnOnce(&mut Connection) -> crate::Result<T> + Send + 'static,
+        {
+            let connection = self.connection.clone();
+            tokio::task::spawn_blocking(move || {
+                let mut connection = connection.lock().expect("sqlite connection poisoned");
+                operation(&mut connection)
+            })
+            .await
+            .expect("sqlite operation panicked")
+        }
+    }
+
+    fn status(connection: &Connection) -> crate::Result<Status> {
+        let status: Option<bool> = connection
+            .query_row(CHECK_STATUS_QUERY, [], |row| row.get(0))
+            .optional()?;
+        Ok(match status {
+            Some(true) => Status::Open,
+            _ => Status::Closed,
+        })
+    }
+
+    #[async_trait]
+    impl SignUpStore for SqliteStore {
+        async fn check(&self, address: H160) -> crate::Result<bool> {
+            let address = format!("{:x}", address);
+            self.run(move |connection| {
+                let result: Option<String> = connection
+                    .query_row(CHECK_SIGNUP_QUERY, [&address], |row| row.get(0))
+                    .optional()?;
+                Ok(result.is_some())
+            })
+            .await
+        }
+
+        async fn sign_up(
+            &self,
+            address: H160,
+            _metadata: Option<&SignUpMetadata>,
+        ) -> crate::Result<SignUpOutcome> {
+            self.run(move |connection| {
+                let key = format!("{:x}", address);
+                // Take the write lock up front so that the status cannot change until the sign-up completes
+                let transaction =
+                    connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+                if matches!(status(&transaction)?, Status::Open) {
+                    let signed_up_at = Utc::now();
+                    if transaction.execute(SIGNUP_COMMAND, params![key, signed_up_at])? == 1 {
+                        transaction.commit()?;
+                        return Ok(SignUpOutcome::Created(SignUp {
+                            address,
+                            signed_up_at,
+                        }));
+                    }
+                }
+
+                let signed_up_at: Option<DateTime<Utc>> = transaction
+                    .query_row(GET_SIGNUP_QUERY, [&key], |row| row.get(0))
+                    .optional()?;
+                transaction.commit()?;
+                match signed_up_at {
+                    Some(signed_up_at) => Ok(SignUpOutcome::Existing(SignUp {
+                        address,
+                        signed_up_at,
+                    })),
+                    None => Err(VIPSignupClosed),
+                }
+            })
+            .await
+        }
+
+        async fn total(&self) -> crate::Result<SignUps> {
+            self.run(|connection| {
+                let status = status(connection)?;
+                let (total, last_signed_up): (i64, Option<DateTime<Utc>>) =
+                    connection.query_row(TOTAL_SIGNUPS_QUERY, [], |row| {
+                        Ok((row.get(0)?, row.get(1)?))
+                    })?;
+                Ok(SignUps {
+                    total: total as u64,
+                    last_signed_up,
+                    status,
+                })
+            })
+            .await
+        }
+
+        async fn healthy(&self) -> crate::Result<()> {
+            self.run(|connection| {
+                connection.query_row("SELECT 1", [], |_| Ok(()))?;
+                Ok(())
+            })
+            .await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::memory::MemoryStore;
+    use super::SignUpStore;
+    use crate::error::Error;
+    use crate::models::{SignUpOutcome, Status};
+    use primitive_types::H160;
+
+    #[tokio::test]
+    async fn memory_store_signs_up_once() {
+        let store = MemoryStore::new(Status::Open);
+        let address = H160::from_low_u64_be(1);
+        assert!(!store.check(address).await.unwrap());
+
+        let Ok(SignUpOutcome::Created(created)) = store.sign_up(address, None).await else {
+            panic!("expected a new sign-up");
+        };
+        assert!(store.check(address).await.unwrap());
+        let Ok(SignUpOutcome::Existing(existing)) = store.sign_up(address, None).await else {
+            panic!("expected an existing sign-up");
+        };
+        assert_eq!(existing.signed_up_at, created.signed_up_at);
+
+        store.sign_up(H160::from_low_u64_be(2), None).await.unwrap();
+        let total = store.total().await.unwrap();
+        assert_eq!(total.total, 2);
+        assert!(total.last_signed_up >= Some(created.signed_up_at));
+        assert!(matches!(total.status, Status::Open));
+    }
+
+    #[tokio::test]
+    async fn memory_store_rejects_new_sign_ups_once_closed() {
+        let store = MemoryStore::new(Status::Closed);
+        let address = H160::from_low_u64_be(1);
+        assert!(matches!(
+            store.sign_up(address, None).await,
+            Err(Error::VIPSignupClosed)
+        ));
+        assert!(!store.check(address).await.unwrap());
+        let total = store.total().await.unwrap();
+        assert_eq!(total.total, 0);
+        assert_eq!(total.last_signed_up, None);
+        assert!(matches!(total.status, Status::Closed));
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn sqlite_store_is_closed_until_opened() {
+        let store = super::sqlite::SqliteStore::open(":memory:").unwrap();
+        let address = H160::from_low_u64_be(1);
+        assert!(matches!(
+            store.total().await.unwrap().status,
+            Status::Closed
+        ));
+        assert!(matches!(
+            store.sign_up(address, None).await,
+            Err(Error::VIPSignupClosed)
+        ));
+
+        store.set_status(Status::Open).await.unwrap();
+        assert!(matches!(
+            store.sign_up(address, None).await.unwrap(),
+            SignUpOutcome::Created(_)
+        ));
+        store.set_status(Status::Closed).await.unwrap();
+        assert!(matches!(
+            store.sign_up(address, None).await.unwrap(),
+            SignUpOutcome::Existing(_)
+        ));
+        assert_eq!(store.total().await.unwrap().total, 1);
+    }
+}